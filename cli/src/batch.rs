@@ -0,0 +1,454 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+};
+
+use anchor_client::{
+    solana_client::{nonblocking::rpc_client::RpcClient, rpc_config::RpcTransactionConfig},
+    solana_sdk::{
+        commitment_config::CommitmentConfig, hash::Hash, pubkey::Pubkey, signature::{Keypair, Signature},
+        signer::Signer,
+    },
+    Program,
+};
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use serde::{Deserialize, Serialize};
+use solana_transaction_status_client_types::UiTransactionEncoding;
+
+use crate::{
+    build_unstake_lst_transaction, get_stake_pool_program_for_lst_mint, send_or_simulate_transaction,
+    send_or_simulate_transaction_checked, PubkeyOrKeypair, UnstakeLstOptions,
+};
+
+/// A single unstake request of a batch file
+#[derive(Clone, Debug)]
+pub struct BatchRow {
+    pub mint: Pubkey,
+    pub amount: u64,
+    /// Wallet receiving the SOL, defaults to the wallet doing the unstake
+    pub recipient: Option<Pubkey>,
+    pub minimum_lamports_out: Option<u64>,
+}
+
+/// A row as found in a JSON batch file
+#[derive(Deserialize)]
+struct JsonBatchRow {
+    mint: String,
+    amount: u64,
+    recipient: Option<String>,
+    min_out: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchRowStatus {
+    /// The transaction was sent but it is not known yet whether it landed
+    Pending,
+    Landed,
+    Failed,
+    Simulated,
+}
+
+/// Result of processing a batch row, one JSON line per state change is appended to the output file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchRowResult {
+    pub row: usize,
+    pub mint: String,
+    pub amount: u64,
+    pub status: BatchRowStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blockhash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamports_received: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchRowResult {
+    fn new(row: usize, batch_row: &BatchRow, status: BatchRowStatus) -> Self {
+        BatchRowResult {
+            row,
+            mint: batch_row.mint.to_string(),
+            amount: batch_row.amount,
+            status,
+            signature: None,
+            blockhash: None,
+            lamports_received: None,
+            error: None,
+        }
+    }
+}
+
+/// Function to read the rows of a batch file, JSON if the file has a `.json` extension, otherwise CSV
+/// with the columns `mint,amount,recipient,min_out` where the last two are optional
+pub fn read_batch_file(path: &Path) -> Result<Vec<BatchRow>> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read batch file {}: {}", path.display(), err))?;
+
+    let parse_pubkey = |value: &str| {
+        Pubkey::from_str(value).map_err(|_| anyhow::anyhow!("Invalid public key {} in batch file", value))
+    };
+
+    if path.extension().is_some_and(|extension| extension == "json") {
+        return serde_json::from_str::<Vec<JsonBatchRow>>(&content)?
+            .into_iter()
+            .map(|row| {
+                Ok(BatchRow {
+                    mint: parse_pubkey(&row.mint)?,
+                    amount: row.amount,
+                    recipient: row.recipient.as_deref().map(parse_pubkey).transpose()?,
+                    minimum_lamports_out: row.min_out,
+                })
+            })
+            .collect();
+    }
+
+    let mut rows = vec![];
+
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let columns = line.split(',').map(str::trim).collect::<Vec<_>>();
+
+        // Skip the header
+        if rows.is_empty() && columns[0] == "mint" {
+            continue;
+        }
+
+        if columns.len() < 2 || columns.len() > 4 {
+            return Err(anyhow::anyhow!("Invalid batch file line {}: {}", line_number + 1, line));
+        }
+
+        let optional_column = |index: usize| columns.get(index).copied().filter(|value| !value.is_empty());
+
+        rows.push(BatchRow {
+            mint: parse_pubkey(columns[0])?,
+            amount: columns[1]
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid amount on batch file line {}", line_number + 1))?,
+            recipient: optional_column(2).map(parse_pubkey).transpose()?,
+            minimum_lamports_out: optional_column(3)
+                .map(|value| value.parse())
+                .transpose()
+                .map_err(|_| anyhow::anyhow!("Invalid min_out on batch file line {}", line_number + 1))?,
+        });
+    }
+
+    Ok(rows)
+}
+
+/// Function to read the latest result for every row from an output file of a previous batch run
+pub fn read_batch_results(path: &Path) -> Result<HashMap<usize, BatchRowResult>> {
+    let mut results = HashMap::new();
+
+    if !path.exists() {
+        return Ok(results);
+    }
+
+    for line in fs::read_to_string(path)?.lines().filter(|line| !line.trim().is_empty()) {
+        let result = serde_json::from_str::<BatchRowResult>(line)?;
+        results.insert(result.row, result);
+    }
+
+    Ok(results)
+}
+
+fn append_batch_result(path: &Path, result: &BatchRowResult) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(result)?)?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Function to get the lamports received by `account` in a confirmed transaction, the transaction fee is
+/// added back if the account is also the fee payer
+pub async fn get_lamports_received(rpc: &RpcClient, signature: &Signature, account: &Pubkey) -> Result<i64> {
+    let tx = rpc
+        .get_transaction_with_config(
+            signature,
+            RpcTransactionConfig {
+                encoding: Some(UiTransactionEncoding::Base64),
+                commitment: Some(CommitmentConfig::confirmed()),
+                max_supported_transaction_version: Some(0),
+            },
+        )
+        .await?;
+
+    let meta = tx
+        .transaction
+        .meta
+        .ok_or(anyhow::anyhow!("No metadata found for transaction {}", signature))?;
+    let decoded_tx = tx
+        .transaction
+        .transaction
+        .decode()
+        .ok_or(anyhow::anyhow!("Unable to decode transaction {}", signature))?;
+
+    let account_keys = decoded_tx.message.static_account_keys();
+    let index = account_keys
+        .iter()
+        .position(|key| key == account)
+        .ok_or(anyhow::anyhow!("Account {} not found in transaction {}", account, signature))?;
+
+    let mut lamports_received = meta.post_balances[index] as i64 - meta.pre_balances[index] as i64;
+
+    // The fee payer is always the first account
+    if index == 0 {
+        lamports_received += meta.fee as i64;
+    }
+
+    Ok(lamports_received)
+}
+
+/// Outcome of a transaction that was sent without knowing whether it landed
+pub enum PendingOutcome {
+    Landed,
+    Failed(String),
+    /// Not seen by the cluster yet but the blockhash is still valid, so it may still land
+    StillPending,
+    /// The blockhash expired without the transaction landing, it is safe to send again
+    Dropped,
+}
+
+/// Function to find out whether a sent transaction landed, failed or can no longer land
+pub async fn resolve_pending(rpc: &RpcClient, signature: &Signature, blockhash: &Hash) -> Result<PendingOutcome> {
+    let status = rpc
+        .get_signature_statuses_with_history(&[*signature])
+        .await?
+        .value
+        .pop()
+        .flatten();
+
+    match status {
+        Some(status) => match status.err {
+            None => Ok(PendingOutcome::Landed),
+            Some(err) => Ok(PendingOutcome::Failed(err.to_string())),
+        },
+        None => {
+            if rpc.is_blockhash_valid(blockhash, CommitmentConfig::processed()).await? {
+                Ok(PendingOutcome::StillPending)
+            } else {
+                Ok(PendingOutcome::Dropped)
+            }
+        }
+    }
+}
+
+/// Unstake all rows of a batch with bounded concurrency, recording the results to `output`.
+///
+/// Rows that already landed (or may still land) according to `output` are not sent again, so an
+/// interrupted batch can be resumed by running it again with the same input and output files
+#[allow(clippy::too_many_arguments)]
+pub async fn run_batch(
+    program: &Program<Rc<Keypair>>,
+    unstake_pool_id: &Pubkey,
+    fee_payer: &Keypair,
+    wallet_keypair: &Keypair,
+    rows: &[BatchRow],
+    output: &Path,
+    concurrency: usize,
+    simulate: bool,
+    new_stake_account_as_pda: bool,
+    compute_unit_price: Option<u64>,
+    slippage_bps: Option<u16>,
+) -> Result<()> {
+    let rpc = program.rpc();
+    let previous_results = read_batch_results(output)?;
+    let authority = PubkeyOrKeypair::Keypair(wallet_keypair.insecure_clone());
+
+    // Figure out which rows still need to be sent
+    let mut todo = vec![];
+
+    for (row, batch_row) in rows.iter().enumerate() {
+        let Some(previous_result) = previous_results.get(&row) else {
+            todo.push(row);
+            continue;
+        };
+
+        if previous_result.mint != batch_row.mint.to_string() || previous_result.amount != batch_row.amount {
+            return Err(anyhow::anyhow!(
+                "Row {} in {} does not match the batch file, refusing to resume",
+                row,
+                output.display()
+            ));
+        }
+
+        match previous_result.status {
+            BatchRowStatus::Landed => continue,
+            BatchRowStatus::Failed | BatchRowStatus::Simulated => todo.push(row),
+            BatchRowStatus::Pending => {
+                let (Some(signature), Some(blockhash)) = (&previous_result.signature, &previous_result.blockhash) else {
+                    todo.push(row);
+                    continue;
+                };
+                let signature = Signature::from_str(signature)?;
+                let blockhash = Hash::from_str(blockhash)?;
+
+                match resolve_pending(&rpc, &signature, &blockhash).await? {
+                    PendingOutcome::Landed => {
+                        let mut result = previous_result.clone();
+                        result.status = BatchRowStatus::Landed;
+                        result.lamports_received = get_lamports_received(
+                            &rpc,
+                            &signature,
+                            &batch_row.recipient.unwrap_or(wallet_keypair.pubkey()),
+                        )
+                        .await
+                        .ok();
+
+                        append_batch_result(output, &result)?;
+                    }
+                    PendingOutcome::StillPending => {
+                        println!("Row {} is still pending with signature {}, skipping it for now", row, signature);
+                    }
+                    PendingOutcome::Failed(_) | PendingOutcome::Dropped => todo.push(row),
+                }
+            }
+        }
+    }
+
+    println!("{} of {} rows to process", todo.len(), rows.len());
+
+    // New stake account PDAs are derived from the pool state, which only changes once an unstake lands,
+    // so concurrent unstakes have to use new stake account keypairs instead
+    let new_stake_account_as_pda = new_stake_account_as_pda && concurrency <= 1;
+
+    // Resolve the stake pool program for every mint once, rather than for every row
+    let mut stake_pool_programs = HashMap::new();
+
+    for row in todo.iter() {
+        let mint = rows[*row].mint;
+
+        if let std::collections::hash_map::Entry::Vacant(entry) = stake_pool_programs.entry(mint) {
+            entry.insert(get_stake_pool_program_for_lst_mint(&rpc, &mint).await?);
+        }
+    }
+
+    let results = stream::iter(todo)
+        .map(|row| {
+            let rpc = &rpc;
+            let stake_pool_programs = &stake_pool_programs;
+            let authority = &authority;
+
+            async move {
+                let batch_row = &rows[row];
+                let recipient = batch_row.recipient.unwrap_or(wallet_keypair.pubkey());
+
+                let result: Result<BatchRowResult> = async {
+                    let Some(spl_stake_pool_program_id) = stake_pool_programs[&batch_row.mint] else {
+                        return Err(anyhow::anyhow!("Could not find a supported stake pool for the given mint"));
+                    };
+
+                    let unstake_pool_info = program.account::<Pool>(*unstake_pool_id).await?;
+
+                    let (tx, simulation_accounts_of_interest) = build_unstake_lst_transaction(
+                        program,
+                        unstake_pool_id,
+                        fee_payer,
+                        authority,
+                        &spl_stake_pool_program_id,
+                        &batch_row.mint,
+                        &unstake_pool_info,
+                        batch_row.amount,
+                        new_stake_account_as_pda,
+                        &UnstakeLstOptions {
+                            recipient: Some(recipient),
+                            minimum_lamports_out: batch_row.minimum_lamports_out,
+                            compute_unit_price,
+                            slippage_bps,
+                            ..Default::default()
+                        },
+                    )
+                    .await?;
+
+                    if simulate {
+                        send_or_simulate_transaction(rpc, &tx, true, Some(simulation_accounts_of_interest)).await?;
+
+                        return Ok(BatchRowResult::new(row, batch_row, BatchRowStatus::Simulated));
+                    }
+
+                    // Record the signature before sending, so a resumed batch can tell whether it landed
+                    let mut result = BatchRowResult::new(row, batch_row, BatchRowStatus::Pending);
+                    result.signature = Some(tx.signatures[0].to_string());
+                    result.blockhash = Some(tx.message.recent_blockhash.to_string());
+                    append_batch_result(output, &result)?;
+
+                    let outcome = match send_or_simulate_transaction_checked(rpc, &tx, false, None).await {
+                        Ok(_) => PendingOutcome::Landed,
+                        Err(err) => match resolve_pending(rpc, &tx.signatures[0], &tx.message.recent_blockhash).await {
+                            Ok(PendingOutcome::Dropped) | Err(_) => PendingOutcome::Failed(err.to_string()),
+                            Ok(outcome) => outcome,
+                        },
+                    };
+
+                    match outcome {
+                        PendingOutcome::Landed => {
+                            result.status = BatchRowStatus::Landed;
+                            result.lamports_received =
+                                get_lamports_received(rpc, &tx.signatures[0], &recipient).await.ok();
+                        }
+                        PendingOutcome::Failed(err) => {
+                            result.status = BatchRowStatus::Failed;
+                            result.error = Some(err);
+                        }
+                        PendingOutcome::StillPending | PendingOutcome::Dropped => {}
+                    }
+
+                    Ok(result)
+                }
+                .await;
+
+                let result = result.unwrap_or_else(|err| {
+                    let mut result = BatchRowResult::new(row, batch_row, BatchRowStatus::Failed);
+                    result.error = Some(err.to_string());
+                    result
+                });
+
+                append_batch_result(output, &result)?;
+
+                println!(
+                    "Row {}: {:?} {}",
+                    row,
+                    result.status,
+                    result
+                        .lamports_received
+                        .map(|lamports| format!("{} lamports received", lamports))
+                        .or(result.error.clone())
+                        .unwrap_or_default()
+                );
+
+                Ok::<_, anyhow::Error>(result)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+    let landed = results.iter().filter(|result| result.status == BatchRowStatus::Landed).count();
+    let failed = results.iter().filter(|result| result.status == BatchRowStatus::Failed).count();
+
+    println!(
+        "Processed {} rows: {} landed, {} failed, results written to {}",
+        results.len(),
+        landed,
+        failed,
+        output.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,317 @@
+use std::{collections::HashMap, fs, path::PathBuf, str::FromStr};
+
+use anchor_client::solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+use anchor_lang::prelude::pubkey;
+use anyhow::Result;
+use clap::ArgMatches;
+use serde::Deserialize;
+use url::Url;
+
+/// The main The Vault unstake pool
+pub const MAIN_POOL: Pubkey = pubkey!("9nyw5jxhzuSs88HxKJyDCsWBZMhxj2uNXsFcyHF5KBAb");
+
+/// Pools that can be referred to by name, in addition to the pools of the config file
+const NAMED_POOLS: [(&str, Pubkey); 1] = [("main", MAIN_POOL)];
+
+const DEFAULT_RPC_URL: &str = "https://api.mainnet-beta.solana.com";
+const DEFAULT_PROFILE: &str = "default";
+
+/// The config file, by default `~/.config/liquid-unstaker/config.toml`
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+    /// Additional named pools, name to pool ID
+    #[serde(default)]
+    pub pools: HashMap<String, String>,
+}
+
+/// A named set of settings in the config file, every setting is optional
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Profile {
+    pub cluster_url: Option<String>,
+    pub websocket_url: Option<String>,
+    /// Pool ID or pool name
+    pub pool: Option<String>,
+    /// Liquid unstaker program ID, for deployments other than the mainnet one
+    pub program_id: Option<String>,
+    pub keypair: Option<String>,
+    pub commitment: Option<String>,
+    /// Priority fee in micro-lamports per compute unit
+    pub priority_fee: Option<u64>,
+    /// Default slippage for unstakes in basis points
+    pub slippage_bps: Option<u16>,
+}
+
+/// The settings of the Solana CLI config, `~/.config/solana/cli/config.yml`, that we fall back to
+#[derive(Debug, Default)]
+struct SolanaCliConfig {
+    json_rpc_url: Option<String>,
+    websocket_url: Option<String>,
+    keypair_path: Option<String>,
+    commitment: Option<String>,
+}
+
+/// The resolved settings, taken from (in order of precedence) the command line, environment variables,
+/// the config file profile and the Solana CLI config
+#[derive(Debug)]
+pub struct Config {
+    pub rpc_url: String,
+    pub websocket_url: String,
+    pub pool: Option<Pubkey>,
+    pub program_id: Pubkey,
+    /// Wallet keypair path, None if no keypair is configured
+    pub keypair_path: Option<String>,
+    /// Whether the keypair has to exist, the keypair of the Solana CLI config is only used if it exists
+    pub keypair_required: bool,
+    pub commitment: CommitmentConfig,
+    pub priority_fee: Option<u64>,
+    pub slippage_bps: Option<u16>,
+}
+
+impl Config {
+    pub fn load(matches: &ArgMatches) -> Result<Config> {
+        let config_file_path = arg_or_env(matches, "config", "LIQUID_UNSTAKER_CONFIG");
+        let config_file = match &config_file_path {
+            Some(path) => read_config_file(&expand_home(path))?,
+            None => {
+                let default_path = get_config_dir().map(|config_dir| config_dir.join("config.toml"));
+
+                match default_path {
+                    Some(path) if path.exists() => read_config_file(&path)?,
+                    _ => ConfigFile::default(),
+                }
+            }
+        };
+
+        let profile_name = arg_or_env(matches, "profile", "LIQUID_UNSTAKER_PROFILE");
+        let profile = match &profile_name {
+            Some(profile_name) => config_file
+                .profiles
+                .get(profile_name)
+                .cloned()
+                .ok_or(anyhow::anyhow!("Profile {} not found in the config file", profile_name))?,
+            None => config_file.profiles.get(DEFAULT_PROFILE).cloned().unwrap_or_default(),
+        };
+
+        let solana_cli_config = read_solana_cli_config();
+
+        let rpc_url = arg_or_env(matches, "rpc", "LIQUID_UNSTAKER_RPC")
+            .or(profile.cluster_url)
+            .or(solana_cli_config.json_rpc_url)
+            .unwrap_or(DEFAULT_RPC_URL.to_string());
+
+        let websocket_url = arg_or_env(matches, "ws", "LIQUID_UNSTAKER_WS")
+            .or(profile.websocket_url)
+            .or(solana_cli_config.websocket_url.filter(|url| !url.is_empty()))
+            .map_or_else(|| compute_websocket_url(&rpc_url), Ok)?;
+
+        let pool = arg_or_env(matches, "pool", "LIQUID_UNSTAKER_POOL")
+            .or(profile.pool)
+            .map(|pool| resolve_pool(&pool, &config_file.pools))
+            .transpose()?;
+
+        let program_id = arg_or_env(matches, "program-id", "LIQUID_UNSTAKER_PROGRAM_ID")
+            .or(profile.program_id)
+            .map(|program_id| {
+                Pubkey::from_str(&program_id).map_err(|_| anyhow::anyhow!("Invalid program ID {}", program_id))
+            })
+            .transpose()?
+            .unwrap_or(liquid_unstaker::liquid_unstaker::ID_CONST);
+
+        let explicit_keypair_path = arg_or_env(matches, "keypair", "LIQUID_UNSTAKER_KEYPAIR").or(profile.keypair);
+        let keypair_required = explicit_keypair_path.is_some();
+        let keypair_path = explicit_keypair_path
+            .or(solana_cli_config.keypair_path)
+            .map(|path| expand_home(&path).to_string_lossy().to_string());
+
+        let commitment = arg_or_env(matches, "commitment", "LIQUID_UNSTAKER_COMMITMENT")
+            .or(profile.commitment)
+            .or(solana_cli_config.commitment)
+            .map(|commitment| {
+                CommitmentConfig::from_str(&commitment)
+                    .map_err(|_| anyhow::anyhow!("Invalid commitment {}", commitment))
+            })
+            .transpose()?
+            .unwrap_or(CommitmentConfig::confirmed());
+
+        let priority_fee = arg_or_env(matches, "priority-fee", "LIQUID_UNSTAKER_PRIORITY_FEE")
+            .map(|priority_fee| priority_fee.parse())
+            .transpose()
+            .map_err(|_| anyhow::anyhow!("Invalid priority fee"))?
+            .or(profile.priority_fee);
+
+        let slippage_bps = arg_or_env(matches, "slippage-bps", "LIQUID_UNSTAKER_SLIPPAGE_BPS")
+            .map(|slippage_bps| slippage_bps.parse())
+            .transpose()
+            .map_err(|_| anyhow::anyhow!("Invalid slippage"))?
+            .or(profile.slippage_bps);
+
+        Ok(Config {
+            rpc_url,
+            websocket_url,
+            pool,
+            program_id,
+            keypair_path,
+            keypair_required,
+            commitment,
+            priority_fee,
+            slippage_bps,
+        })
+    }
+}
+
+/// Resolve a pool ID or a pool name, from the config file or the built-in names, to a pool ID
+pub fn resolve_pool(pool: &str, config_file_pools: &HashMap<String, String>) -> Result<Pubkey> {
+    if let Ok(pool_id) = Pubkey::from_str(pool) {
+        return Ok(pool_id);
+    }
+
+    if let Some(pool_id) = config_file_pools.get(pool) {
+        return Pubkey::from_str(pool_id)
+            .map_err(|_| anyhow::anyhow!("Invalid pool ID {} for pool {} in the config file", pool_id, pool));
+    }
+
+    NAMED_POOLS
+        .iter()
+        .find(|(name, _)| *name == pool)
+        .map(|(_, pool_id)| *pool_id)
+        .ok_or(anyhow::anyhow!("Unknown pool {}", pool))
+}
+
+/// Function to derive the websocket URL from the RPC URL the way the Solana CLI does: ws for http, wss for https
+/// and the port after the RPC port, if the URL has one
+pub fn compute_websocket_url(rpc_url: &str) -> Result<String> {
+    let mut url = Url::parse(rpc_url).map_err(|err| anyhow::anyhow!("Invalid RPC URL {}: {}", rpc_url, err))?;
+
+    let scheme = match url.scheme() {
+        "http" => "ws",
+        "https" => "wss",
+        "ws" | "wss" => return Ok(rpc_url.to_string()),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Cannot derive a websocket URL from the RPC URL {}, set it with --ws",
+                rpc_url
+            ))
+        }
+    };
+
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow::anyhow!("Cannot derive a websocket URL from the RPC URL {}", rpc_url))?;
+
+    if let Some(port) = url.port() {
+        let port = port
+            .checked_add(1)
+            .ok_or(anyhow::anyhow!("Cannot derive a websocket port from the RPC URL {}", rpc_url))?;
+
+        url.set_port(Some(port))
+            .map_err(|_| anyhow::anyhow!("Cannot derive a websocket URL from the RPC URL {}", rpc_url))?;
+    }
+
+    Ok(url.to_string())
+}
+
+fn arg_or_env(matches: &ArgMatches, arg: &str, env_var: &str) -> Option<String> {
+    matches
+        .get_one::<String>(arg)
+        .cloned()
+        .or(std::env::var(env_var).ok().filter(|value| !value.is_empty()))
+}
+
+fn read_config_file(path: &PathBuf) -> Result<ConfigFile> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| anyhow::anyhow!("Failed to read config file {}: {}", path.display(), err))?;
+
+    toml::from_str(&content).map_err(|err| anyhow::anyhow!("Invalid config file {}: {}", path.display(), err))
+}
+
+/// Read the Solana CLI config if it exists
+fn read_solana_cli_config() -> SolanaCliConfig {
+    home_dir()
+        .map(|home| home.join(".config/solana/cli/config.yml"))
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|content| parse_solana_cli_config(&content))
+        .unwrap_or_default()
+}
+
+/// Parse the Solana CLI config. It is a flat YAML file, so a line based parser is all we need
+fn parse_solana_cli_config(content: &str) -> SolanaCliConfig {
+    let mut config = SolanaCliConfig::default();
+
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+
+        let value = Some(value.trim().trim_matches(|c| c == '"' || c == '\'').to_string());
+
+        match key.trim() {
+            "json_rpc_url" => config.json_rpc_url = value,
+            "websocket_url" => config.websocket_url = value,
+            "keypair_path" => config.keypair_path = value,
+            "commitment" => config.commitment = value,
+            _ => {}
+        }
+    }
+
+    config
+}
+
+/// The directory of the config file and other local state, `~/.config/liquid-unstaker`
+pub fn get_config_dir() -> Option<PathBuf> {
+    home_dir().map(|home| home.join(".config/liquid-unstaker"))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var("HOME").ok().map(PathBuf::from)
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_websocket_url() {
+        assert_eq!(
+            compute_websocket_url("https://api.mainnet-beta.solana.com").unwrap(),
+            "wss://api.mainnet-beta.solana.com/"
+        );
+        assert_eq!(compute_websocket_url("http://127.0.0.1:8899").unwrap(), "ws://127.0.0.1:8900/");
+        assert_eq!(
+            compute_websocket_url("https://rpc.example.com:443/api-key?x=1").unwrap(),
+            "wss://rpc.example.com/api-key?x=1"
+        );
+        assert_eq!(compute_websocket_url("wss://rpc.example.com/ws").unwrap(), "wss://rpc.example.com/ws");
+
+        assert!(compute_websocket_url("ftp://rpc.example.com").is_err());
+        assert!(compute_websocket_url("http://127.0.0.1:65535").is_err());
+        assert!(compute_websocket_url("not a url").is_err());
+    }
+
+    #[test]
+    fn test_parse_solana_cli_config() {
+        let config = parse_solana_cli_config(
+            "---\n\
+             json_rpc_url: \"https://api.devnet.solana.com\"\n\
+             websocket_url: ''\n\
+             keypair_path: /home/user/.config/solana/id.json\n\
+             address_labels:\n  \"11111111111111111111111111111111\": System Program\n\
+             commitment: confirmed\n",
+        );
+
+        assert_eq!(config.json_rpc_url.as_deref(), Some("https://api.devnet.solana.com"));
+        assert_eq!(config.websocket_url.as_deref(), Some(""));
+        assert_eq!(config.keypair_path.as_deref(), Some("/home/user/.config/solana/id.json"));
+        assert_eq!(config.commitment.as_deref(), Some("confirmed"));
+
+        let config = parse_solana_cli_config("");
+        assert!(config.json_rpc_url.is_none() && config.keypair_path.is_none());
+    }
+}
@@ -0,0 +1,157 @@
+use anyhow::Result;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+
+use crate::fee::{Fee, FEE_PCT_DIVISOR};
+
+/// Height of the ASCII chart in rows
+const CHART_HEIGHT: usize = 16;
+
+/// A single unstake size of the fee curve
+pub struct FeeCurvePoint {
+    /// Lamports unstaked
+    pub unstake_lamports: u64,
+    /// Fee charged over the whole unstake including the manager fee, in percent
+    pub effective_fee_pct: f64,
+    /// Fee charged on the last lamport of the unstake including the manager fee, in percent
+    pub marginal_fee_pct: f64,
+    /// Lamports received after fees
+    pub net_lamports_out: u64,
+}
+
+/// Function to calculate the fee curve of the pool, `steps` evenly spaced unstake sizes up to `max_unstake_lamports`
+/// against a SOL vault holding `sol_vault_lamports`
+pub fn get_fee_curve(
+    pool: &Pool,
+    sol_vault_lamports: u64,
+    max_unstake_lamports: u64,
+    steps: u64,
+) -> Result<Vec<FeeCurvePoint>> {
+    let max_unstake_lamports = max_unstake_lamports.min(sol_vault_lamports);
+
+    // The manager fee is charged on top of the base fee, see quote_lst_unstake
+    let total_fee_pct = |base_fee: u64| {
+        base_fee as f64 * (100 + pool.manager_fee_pct as u64) as f64 / 100.0 * 100.0 / FEE_PCT_DIVISOR as f64
+    };
+
+    (1..=steps.max(1))
+        .map(|step| {
+            let unstake_lamports = (max_unstake_lamports as u128 * step as u128 / steps.max(1) as u128) as u64;
+
+            let base_fee = Fee::calculate_base_fee(pool, sol_vault_lamports, unstake_lamports)?;
+            let marginal_base_fee = Fee::calculate_base_fee(pool, sol_vault_lamports - unstake_lamports, 0)?;
+
+            let fee = Fee {
+                base_fee: (base_fee as u128 * unstake_lamports as u128 / FEE_PCT_DIVISOR as u128) as u64,
+                manager_fee: (base_fee as u128 * unstake_lamports as u128 * pool.manager_fee_pct as u128
+                    / (100 * FEE_PCT_DIVISOR as u128)) as u64,
+            };
+
+            Ok(FeeCurvePoint {
+                unstake_lamports,
+                effective_fee_pct: total_fee_pct(base_fee),
+                marginal_fee_pct: total_fee_pct(marginal_base_fee),
+                net_lamports_out: unstake_lamports.saturating_sub(fee.total_fee()),
+            })
+        })
+        .collect()
+}
+
+/// Function to render the effective (`*`) and marginal (`+`) fee against the unstake size as an ASCII chart,
+/// one column per point
+pub fn render_fee_curve_chart(points: &[FeeCurvePoint]) -> String {
+    let max_fee_pct = points
+        .iter()
+        .map(|point| point.effective_fee_pct.max(point.marginal_fee_pct))
+        .fold(0.0, f64::max);
+
+    if points.is_empty() || max_fee_pct <= 0.0 {
+        return String::new();
+    }
+
+    let row_of = |fee_pct: f64| ((fee_pct / max_fee_pct) * (CHART_HEIGHT - 1) as f64).round() as usize;
+
+    let mut chart = String::new();
+
+    for row in (0..CHART_HEIGHT).rev() {
+        let label = max_fee_pct * row as f64 / (CHART_HEIGHT - 1) as f64;
+        chart.push_str(&format!("{:>8.3}% |", label));
+
+        for point in points {
+            let c = if row_of(point.effective_fee_pct) == row {
+                '*'
+            } else if row_of(point.marginal_fee_pct) == row {
+                '+'
+            } else {
+                ' '
+            };
+
+            chart.push(c);
+        }
+
+        chart.push('\n');
+    }
+
+    chart.push_str(&format!("{:>10}+{}\n", "", "-".repeat(points.len())));
+    chart.push_str(&format!(
+        "{:>11}0{:>width$} lamports unstaked\n",
+        "",
+        points.last().unwrap().unstake_lamports,
+        width = points.len().saturating_sub(1)
+    ));
+    chart.push_str("           * effective fee, + marginal fee\n");
+
+    chart
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    #[test]
+    fn test_fee_curve_above_min_fee_threshold() {
+        let pool = get_test_pool();
+        let points = get_fee_curve(&pool, pool.sol_vault_lamports, 1_000 * LAMPORTS_PER_SOL, 4).unwrap();
+
+        // The vault stays above the threshold, so fee_min plus the 10% manager fee applies throughout
+        for point in points.iter() {
+            assert!((point.effective_fee_pct - 0.11).abs() < 1e-9);
+            assert!((point.marginal_fee_pct - 0.11).abs() < 1e-9);
+        }
+
+        assert_eq!(points.last().unwrap().unstake_lamports, 1_000 * LAMPORTS_PER_SOL);
+        assert_eq!(points.last().unwrap().net_lamports_out, 1_000 * LAMPORTS_PER_SOL * 9_989 / 10_000);
+    }
+
+    #[test]
+    fn test_fee_curve_blends_across_min_fee_threshold() {
+        let pool = get_test_pool();
+        let points = get_fee_curve(&pool, pool.sol_vault_lamports, pool.sol_vault_lamports, 20).unwrap();
+
+        // Past the threshold the effective fee blends fee_min on the part above it with the linear fee below it,
+        // so it rises slower than the marginal fee, which reaches fee_max once the vault is empty
+        for (previous, point) in points.iter().tuple_windows() {
+            assert!(point.effective_fee_pct >= previous.effective_fee_pct);
+            assert!(point.marginal_fee_pct >= previous.marginal_fee_pct);
+            assert!(point.effective_fee_pct <= point.marginal_fee_pct);
+        }
+
+        let last = points.last().unwrap();
+        assert!((last.marginal_fee_pct - 5.5).abs() < 1e-9);
+        assert!(last.effective_fee_pct > 0.11 && last.effective_fee_pct < 5.5);
+    }
+
+    #[test]
+    fn test_render_fee_curve_chart() {
+        let pool = get_test_pool();
+        let points = get_fee_curve(&pool, pool.sol_vault_lamports, pool.sol_vault_lamports, 10).unwrap();
+        let chart = render_fee_curve_chart(&points);
+
+        assert_eq!(chart.lines().count(), CHART_HEIGHT + 3);
+        assert!(chart.contains('*') && chart.contains('+'));
+        assert!(render_fee_curve_chart(&[]).is_empty());
+    }
+}
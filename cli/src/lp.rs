@@ -0,0 +1,387 @@
+use anchor_lang::prelude::*;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+
+use crate::error::LiquidUnstakerErrorCode;
+
+/// Minimum delegation of a stake account, both parts of a split stake account need at least this much stake
+pub const MIN_STAKE_DELEGATION: u64 = 1_000_000_000;
+
+/// Divisor of the withdraw fees of the pool (`withdraw_sol_fee`, `withdraw_stake_account_fee`), in basis points
+pub const WITHDRAW_FEE_DIVISOR: u64 = 10_000;
+
+/// Quote of withdrawing LP tokens from the pool
+pub struct WithdrawQuote {
+    pub lp_tokens: u64,
+    /// Lamports the LP tokens are worth before the withdraw fee
+    pub lamports: u64,
+    pub withdraw_fee: u64,
+    /// Lamports received
+    pub lamports_out: u64,
+    /// Whether the SOL vault holds enough lamports for the withdrawal, part of the pool value may still be
+    /// deactivating stake
+    pub sufficient_liquidity: bool,
+}
+
+/// Lamports backing the LP tokens, the SOL vault plus the stake that is still deactivating
+pub fn get_pool_value_lamports(pool: &Pool) -> Result<u64> {
+    pool.sol_vault_lamports
+        .checked_add(pool.total_deactivating_stake)
+        .ok_or(error!(LiquidUnstakerErrorCode::MathOverflow))
+}
+
+/// Function to get the LP tokens minted for depositing the given lamports, the first deposit mints one LP token per
+/// lamport
+pub fn quote_deposit(pool: &Pool, lamports: u64) -> Result<u64> {
+    if lamports == 0 {
+        return err!(LiquidUnstakerErrorCode::DepositMustBeLargerThanZero);
+    }
+
+    let sol_vault_lamports = pool
+        .sol_vault_lamports
+        .checked_add(lamports)
+        .ok_or(LiquidUnstakerErrorCode::MathOverflow)?;
+
+    if sol_vault_lamports > pool.sol_vault_lamports_cap {
+        return err!(LiquidUnstakerErrorCode::SolVaultLamportsCapReached);
+    }
+
+    let pool_value_lamports = get_pool_value_lamports(pool)?;
+
+    let lp_tokens = if pool.total_lp_tokens == 0 || pool_value_lamports == 0 {
+        lamports
+    } else {
+        u64::try_from(lamports as u128 * pool.total_lp_tokens as u128 / pool_value_lamports as u128)
+            .map_err(|_| error!(LiquidUnstakerErrorCode::MathOverflow))?
+    };
+
+    if lp_tokens == 0 {
+        return err!(LiquidUnstakerErrorCode::LpTokensToMintIsZero);
+    }
+
+    Ok(lp_tokens)
+}
+
+/// Function to get the lamports returned for withdrawing the given LP tokens as SOL, after the withdraw fee
+pub fn quote_withdraw_sol(pool: &Pool, lp_tokens: u64) -> Result<WithdrawQuote> {
+    if lp_tokens > pool.total_lp_tokens {
+        return err!(LiquidUnstakerErrorCode::InsufficientLpTokenBalance);
+    }
+
+    let lamports = get_lp_tokens_value(pool, lp_tokens)?;
+
+    let withdraw_fee = u64::try_from(lamports as u128 * pool.withdraw_sol_fee as u128 / WITHDRAW_FEE_DIVISOR as u128)
+        .map_err(|_| error!(LiquidUnstakerErrorCode::MathOverflow))?;
+
+    Ok(WithdrawQuote {
+        lp_tokens,
+        lamports,
+        withdraw_fee,
+        lamports_out: lamports - withdraw_fee,
+        sufficient_liquidity: lamports <= pool.sol_vault_lamports,
+    })
+}
+
+/// Function to get the lamports the given LP tokens are worth at the current share price
+pub fn get_lp_tokens_value(pool: &Pool, lp_tokens: u64) -> Result<u64> {
+    if pool.total_lp_tokens == 0 {
+        return Ok(0);
+    }
+
+    u64::try_from(lp_tokens as u128 * get_pool_value_lamports(pool)? as u128 / pool.total_lp_tokens as u128)
+        .map_err(|_| error!(LiquidUnstakerErrorCode::MathOverflow))
+}
+
+/// Function to get the LP tokens to withdraw to receive at least `lamports_out` lamports after the withdraw fee
+pub fn get_lp_tokens_for_withdraw_sol(pool: &Pool, lamports_out: u64) -> Result<u64> {
+    let pool_value_lamports = get_pool_value_lamports(pool)?;
+
+    if pool.total_lp_tokens == 0 || pool_value_lamports == 0 {
+        return err!(LiquidUnstakerErrorCode::InsufficientLpTokenBalance);
+    }
+
+    // Gross up for the withdraw fee, then convert to LP tokens, rounding up both times
+    let fee_complement = WITHDRAW_FEE_DIVISOR
+        .checked_sub(pool.withdraw_sol_fee as u64)
+        .filter(|fee_complement| *fee_complement > 0)
+        .ok_or(LiquidUnstakerErrorCode::MathUnderflow)? as u128;
+    let lamports = (lamports_out as u128 * WITHDRAW_FEE_DIVISOR as u128).div_ceil(fee_complement);
+    let lp_tokens = (lamports * pool.total_lp_tokens as u128).div_ceil(pool_value_lamports as u128);
+
+    u64::try_from(lp_tokens).map_err(|_| error!(LiquidUnstakerErrorCode::MathOverflow))
+}
+
+/// A withdraw_stake_account of LP tokens, splitting stake off one of the deactivating stake accounts of the pool
+pub struct StakeWithdrawal {
+    pub stake_account: Pubkey,
+    pub lp_tokens: u64,
+    pub withdraw_fee: u64,
+    /// Lamports of stake split off into the new stake account
+    pub stake_lamports: u64,
+}
+
+/// How a withdrawal of LP tokens is split between the SOL vault and the deactivating stake accounts of the pool
+pub struct WithdrawPlan {
+    /// LP tokens withdrawn as SOL through withdraw_sol
+    pub sol_lp_tokens: u64,
+    pub stake_withdrawals: Vec<StakeWithdrawal>,
+}
+
+/// Function to get the stake split off the given stake account for withdrawing the given LP tokens, after the
+/// withdraw fee
+pub fn quote_withdraw_stake(pool: &Pool, stake_account: Pubkey, lp_tokens: u64) -> Result<StakeWithdrawal> {
+    if lp_tokens > pool.total_lp_tokens {
+        return err!(LiquidUnstakerErrorCode::InsufficientLpTokenBalance);
+    }
+
+    let lamports = get_lp_tokens_value(pool, lp_tokens)?;
+
+    let withdraw_fee =
+        u64::try_from(lamports as u128 * pool.withdraw_stake_account_fee as u128 / WITHDRAW_FEE_DIVISOR as u128)
+            .map_err(|_| error!(LiquidUnstakerErrorCode::MathOverflow))?;
+
+    Ok(StakeWithdrawal {
+        stake_account,
+        lp_tokens,
+        withdraw_fee,
+        stake_lamports: lamports - withdraw_fee,
+    })
+}
+
+/// Function to get the most LP tokens whose withdrawal splits off at most `stake_lamports` lamports of stake after
+/// the withdraw fee
+fn get_max_lp_tokens_for_withdraw_stake(pool: &Pool, stake_lamports: u64) -> Result<u64> {
+    let pool_value_lamports = get_pool_value_lamports(pool)?;
+
+    if pool.total_lp_tokens == 0 || pool_value_lamports == 0 {
+        return Ok(0);
+    }
+
+    // Gross up for the withdraw fee, then convert to LP tokens, rounding down both times. The fee is rounded down
+    // as well, so the stake split off stays below `stake_lamports + 1`
+    let fee_complement = WITHDRAW_FEE_DIVISOR
+        .checked_sub(pool.withdraw_stake_account_fee as u64)
+        .filter(|fee_complement| *fee_complement > 0)
+        .ok_or(LiquidUnstakerErrorCode::MathUnderflow)? as u128;
+    let lamports = stake_lamports as u128 * WITHDRAW_FEE_DIVISOR as u128 / fee_complement;
+    let lp_tokens = lamports * pool.total_lp_tokens as u128 / pool_value_lamports as u128;
+
+    u64::try_from(lp_tokens).map_err(|_| error!(LiquidUnstakerErrorCode::MathOverflow))
+}
+
+/// Function to plan withdrawing the given LP tokens, taking SOL from the vault as far as it goes and the rest as
+/// stake from the given pool stake accounts (address and stake lamports). Both the stake split off and the stake
+/// left in the account need at least the minimum delegation of 1 SOL, so a split is capped at the stake of the
+/// account minus 1 SOL and accounts that cannot meet both limits are skipped, the rest carrying over to the next
+/// account. The withdraw fees stay in the pool and raise the value of the LP tokens withdrawn after them, so every
+/// withdrawal is planned against the pool as the previous ones leave it
+pub fn plan_withdraw(pool: &Pool, lp_tokens: u64, stake_accounts: &[(Pubkey, u64)]) -> Result<WithdrawPlan> {
+    let lamports = get_lp_tokens_value(pool, lp_tokens)?;
+
+    if lamports <= pool.sol_vault_lamports {
+        return Ok(WithdrawPlan {
+            sol_lp_tokens: lp_tokens,
+            stake_withdrawals: vec![],
+        });
+    }
+
+    let pool_value_lamports = get_pool_value_lamports(pool)? as u128;
+    let mut sol_lp_tokens = (pool.sol_vault_lamports as u128 * pool.total_lp_tokens as u128 / pool_value_lamports) as u64;
+
+    let sol_quote = quote_withdraw_sol(pool, sol_lp_tokens)?;
+    let mut pool_after = *pool;
+    pool_after.sol_vault_lamports -= sol_quote.lamports_out;
+    pool_after.total_lp_tokens -= sol_lp_tokens;
+
+    let mut remaining_lp_tokens = lp_tokens - sol_lp_tokens;
+    let mut stake_withdrawals = vec![];
+
+    let mut stake_accounts = stake_accounts.to_vec();
+    stake_accounts.sort_by_key(|(_, stake_lamports)| std::cmp::Reverse(*stake_lamports));
+
+    for (stake_account, stake_lamports) in stake_accounts {
+        if remaining_lp_tokens == 0 {
+            break;
+        }
+
+        let Some(max_split_lamports) = stake_lamports
+            .checked_sub(MIN_STAKE_DELEGATION)
+            .filter(|max_split_lamports| *max_split_lamports >= MIN_STAKE_DELEGATION)
+        else {
+            continue;
+        };
+
+        let mut withdrawal = quote_withdraw_stake(&pool_after, stake_account, remaining_lp_tokens)?;
+
+        if withdrawal.stake_lamports > max_split_lamports {
+            let lp_tokens = get_max_lp_tokens_for_withdraw_stake(&pool_after, max_split_lamports)?;
+            withdrawal = quote_withdraw_stake(&pool_after, stake_account, lp_tokens)?;
+        }
+
+        if withdrawal.stake_lamports < MIN_STAKE_DELEGATION {
+            continue;
+        }
+
+        pool_after.total_deactivating_stake -= withdrawal.stake_lamports;
+        pool_after.total_lp_tokens -= withdrawal.lp_tokens;
+        remaining_lp_tokens -= withdrawal.lp_tokens;
+        stake_withdrawals.push(withdrawal);
+    }
+
+    // Rounding, or stake accounts too small to split, can leave LP tokens that no stake account can take, these go
+    // to the SOL withdrawal as long as the vault still covers it
+    if remaining_lp_tokens > 0 {
+        if get_lp_tokens_value(pool, sol_lp_tokens + remaining_lp_tokens)? > pool.sol_vault_lamports {
+            return err!(LiquidUnstakerErrorCode::InsufficientSolVaultBalance);
+        }
+
+        sol_lp_tokens += remaining_lp_tokens;
+    }
+
+    Ok(WithdrawPlan {
+        sol_lp_tokens,
+        stake_withdrawals,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    /// Pool worth 100 SOL with one LP token per lamport, 10 SOL in the vault and 90 SOL deactivating
+    fn get_illiquid_pool() -> Pool {
+        Pool {
+            total_lp_tokens: 100 * LAMPORTS_PER_SOL,
+            sol_vault_lamports: 10 * LAMPORTS_PER_SOL,
+            total_deactivating_stake: 90 * LAMPORTS_PER_SOL,
+            ..get_test_pool()
+        }
+    }
+
+    #[test]
+    fn test_quote_deposit() {
+        let pool = Pool {
+            total_lp_tokens: 0,
+            sol_vault_lamports: 0,
+            ..get_test_pool()
+        };
+        assert_eq!(quote_deposit(&pool, LAMPORTS_PER_SOL).unwrap(), LAMPORTS_PER_SOL);
+
+        // The pool earned 10% since the LP tokens were minted
+        let pool = Pool {
+            total_lp_tokens: 100 * LAMPORTS_PER_SOL,
+            sol_vault_lamports: 110 * LAMPORTS_PER_SOL,
+            ..get_test_pool()
+        };
+        assert_eq!(quote_deposit(&pool, 11 * LAMPORTS_PER_SOL).unwrap(), 10 * LAMPORTS_PER_SOL);
+
+        assert!(quote_deposit(&pool, 0).is_err());
+        assert!(quote_deposit(
+            &Pool {
+                sol_vault_lamports_cap: 111 * LAMPORTS_PER_SOL,
+                ..pool
+            },
+            2 * LAMPORTS_PER_SOL
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quote_withdraw_sol() {
+        let pool = get_illiquid_pool();
+
+        let quote = quote_withdraw_sol(&pool, 10 * LAMPORTS_PER_SOL).unwrap();
+        assert_eq!(quote.lamports, 10 * LAMPORTS_PER_SOL);
+        assert_eq!(quote.withdraw_fee, LAMPORTS_PER_SOL / 100);
+        assert_eq!(quote.lamports_out, quote.lamports - quote.withdraw_fee);
+        assert!(quote.sufficient_liquidity);
+
+        assert!(!quote_withdraw_sol(&pool, 11 * LAMPORTS_PER_SOL).unwrap().sufficient_liquidity);
+        assert!(quote_withdraw_sol(&pool, 101 * LAMPORTS_PER_SOL).is_err());
+    }
+
+    #[test]
+    fn test_get_lp_tokens_for_withdraw_sol() {
+        let pool = Pool {
+            total_lp_tokens: 97 * LAMPORTS_PER_SOL,
+            ..get_illiquid_pool()
+        };
+
+        for lamports_out in [1, 1_234_567, 5 * LAMPORTS_PER_SOL] {
+            let lp_tokens = get_lp_tokens_for_withdraw_sol(&pool, lamports_out).unwrap();
+            let quote = quote_withdraw_sol(&pool, lp_tokens).unwrap();
+
+            // Rounding up overshoots by a few lamports at most
+            assert!(quote.lamports_out >= lamports_out);
+            assert!(quote.lamports_out <= lamports_out + 3);
+        }
+    }
+
+    #[test]
+    fn test_plan_withdraw_from_sol_vault() {
+        let plan = plan_withdraw(&get_illiquid_pool(), 5 * LAMPORTS_PER_SOL, &[]).unwrap();
+
+        assert_eq!(plan.sol_lp_tokens, 5 * LAMPORTS_PER_SOL);
+        assert!(plan.stake_withdrawals.is_empty());
+    }
+
+    #[test]
+    fn test_plan_withdraw_split_limits() {
+        let pool = get_illiquid_pool();
+        let lp_tokens = 50 * LAMPORTS_PER_SOL;
+        let stake_accounts = [
+            (Pubkey::new_unique(), 20 * LAMPORTS_PER_SOL),
+            (Pubkey::new_unique(), 25 * LAMPORTS_PER_SOL),
+            (Pubkey::new_unique(), 3 * LAMPORTS_PER_SOL / 2),
+        ];
+
+        let plan = plan_withdraw(&pool, lp_tokens, &stake_accounts).unwrap();
+
+        // The largest stake account is split up to the minimum delegation it has to keep, the rest carries over to
+        // the next one and the account too small to split is skipped
+        assert_eq!(plan.stake_withdrawals.len(), 2);
+        assert_eq!(plan.stake_withdrawals[0].stake_account, stake_accounts[1].0);
+        assert_eq!(plan.stake_withdrawals[1].stake_account, stake_accounts[0].0);
+
+        for withdrawal in plan.stake_withdrawals.iter() {
+            let (_, stake_lamports) = stake_accounts
+                .iter()
+                .find(|(stake_account, _)| *stake_account == withdrawal.stake_account)
+                .unwrap();
+
+            assert!(withdrawal.stake_lamports >= MIN_STAKE_DELEGATION);
+            assert!(stake_lamports - withdrawal.stake_lamports >= MIN_STAKE_DELEGATION);
+        }
+
+        assert!(plan.stake_withdrawals[0].stake_lamports > 24 * LAMPORTS_PER_SOL - LAMPORTS_PER_SOL / 1_000);
+
+        assert_eq!(
+            plan.sol_lp_tokens + plan.stake_withdrawals.iter().map(|withdrawal| withdrawal.lp_tokens).sum::<u64>(),
+            lp_tokens
+        );
+    }
+
+    #[test]
+    fn test_plan_withdraw_charges_withdraw_stake_fee() {
+        let pool = get_illiquid_pool();
+        let plan = plan_withdraw(&pool, 30 * LAMPORTS_PER_SOL, &[(Pubkey::new_unique(), 60 * LAMPORTS_PER_SOL)]).unwrap();
+        let withdrawal = &plan.stake_withdrawals[0];
+
+        // 0.2% of the LP tokens value stays in the pool
+        assert!(withdrawal.withdraw_fee > 0);
+        assert_eq!(
+            withdrawal.withdraw_fee,
+            (withdrawal.stake_lamports + withdrawal.withdraw_fee) * pool.withdraw_stake_account_fee as u64
+                / WITHDRAW_FEE_DIVISOR
+        );
+    }
+
+    #[test]
+    fn test_plan_withdraw_without_splittable_stake() {
+        let stake_accounts = [(Pubkey::new_unique(), 3 * LAMPORTS_PER_SOL / 2); 40];
+
+        assert!(plan_withdraw(&get_illiquid_pool(), 50 * LAMPORTS_PER_SOL, &stake_accounts).is_err());
+    }
+}
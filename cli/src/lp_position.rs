@@ -0,0 +1,350 @@
+use std::str::FromStr;
+
+use anchor_client::{
+    solana_client::{
+        nonblocking::rpc_client::RpcClient, rpc_client::GetConfirmedSignaturesForAddress2Config,
+        rpc_config::RpcTransactionConfig,
+    },
+    solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature},
+};
+use anchor_lang::Discriminator;
+use anyhow::Result;
+use liquid_unstaker::liquid_unstaker::client::args::{DepositSol, WithdrawSol, WithdrawStakeAccount};
+use solana_transaction_status_client_types::{UiLoadedAddresses, UiTransactionEncoding, UiTransactionTokenBalance};
+
+/// Maximum number of signatures returned by a single getSignaturesForAddress call
+const SIGNATURES_PAGE_SIZE: usize = 1_000;
+
+pub const SECONDS_PER_YEAR: f64 = 365.25 * 24.0 * 60.0 * 60.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LpEventKind {
+    Deposit,
+    WithdrawSol,
+    WithdrawStakeAccount,
+}
+
+/// A deposit or withdrawal of the pool, reconstructed from a transaction
+pub struct LpEvent {
+    pub signature: Signature,
+    pub slot: u64,
+    pub block_time: Option<i64>,
+    pub kind: LpEventKind,
+    /// LP tokens minted or burned
+    pub lp_tokens: u64,
+    /// Lamports deposited, or received as SOL or stake
+    pub lamports: u64,
+}
+
+/// Cost basis and PnL of an LP position, all amounts in lamports
+pub struct LpPositionSummary {
+    pub lp_tokens: u64,
+    /// Average cost of the LP tokens still held
+    pub cost_basis: u64,
+    /// Current redemption value of the LP tokens still held, before the withdraw fee
+    pub value: u64,
+    pub unrealized_pnl: i64,
+    pub realized_pnl: i64,
+    pub holding_period_days: f64,
+    /// Total PnL over the time-weighted cost basis, annualized
+    pub annualized_yield_pct: Option<f64>,
+}
+
+/// Function to get the deposits and withdrawals of the pool by scanning the transactions of the given address, oldest
+/// first. Pass the LP token account of a wallet for the deposits and withdrawals of that wallet, or the LP mint for
+/// all of them. At most `max_transactions` of the most recent transactions are scanned
+pub async fn get_lp_events(
+    rpc: &RpcClient,
+    program_id: &Pubkey,
+    pool_id: &Pubkey,
+    address: &Pubkey,
+    max_transactions: Option<usize>,
+) -> Result<Vec<LpEvent>> {
+    let max_transactions = max_transactions.unwrap_or(usize::MAX);
+    let mut signatures = vec![];
+    let mut before = None;
+
+    while signatures.len() < max_transactions {
+        let page = rpc
+            .get_signatures_for_address_with_config(
+                address,
+                GetConfirmedSignaturesForAddress2Config {
+                    before,
+                    until: None,
+                    limit: Some(SIGNATURES_PAGE_SIZE),
+                    commitment: Some(CommitmentConfig::confirmed()),
+                },
+            )
+            .await?;
+
+        let page_len = page.len();
+
+        for status in page {
+            let signature = Signature::from_str(&status.signature)?;
+            before = Some(signature);
+
+            if status.err.is_none() {
+                signatures.push(signature);
+            }
+        }
+
+        if page_len < SIGNATURES_PAGE_SIZE {
+            break;
+        }
+    }
+
+    signatures.truncate(max_transactions);
+
+    let mut events = vec![];
+
+    // Signatures are returned newest first
+    for signature in signatures.into_iter().rev() {
+        if let Some(event) = get_lp_event(rpc, &signature, program_id, pool_id).await? {
+            events.push(event);
+        }
+    }
+
+    Ok(events)
+}
+
+/// Function to get the deposit or withdrawal done by the given transaction, if any
+async fn get_lp_event(
+    rpc: &RpcClient,
+    signature: &Signature,
+    program_id: &Pubkey,
+    pool_id: &Pubkey,
+) -> Result<Option<LpEvent>> {
+    let tx = rpc
+        .get_transaction_with_config(
+            signature,
+            RpcTransactionConfig {
+                encoding: Some(UiTransactionEncoding::Base64),
+                commitment: Some(CommitmentConfig::confirmed()),
+                max_supported_transaction_version: Some(0),
+            },
+        )
+        .await?;
+
+    let meta = tx
+        .transaction
+        .meta
+        .ok_or(anyhow::anyhow!("No metadata found for transaction {}", signature))?;
+    let decoded_tx = tx
+        .transaction
+        .transaction
+        .decode()
+        .ok_or(anyhow::anyhow!("Unable to decode transaction {}", signature))?;
+
+    // Static keys first, then the keys loaded from address lookup tables
+    let mut account_keys = decoded_tx.message.static_account_keys().to_vec();
+    if let Some(UiLoadedAddresses { writable, readonly }) = Option::from(meta.loaded_addresses) {
+        for key in writable.iter().chain(readonly.iter()) {
+            account_keys.push(Pubkey::from_str(key)?);
+        }
+    }
+
+    // Find the first instruction of the program on this pool
+    let Some((kind, instruction)) = decoded_tx.message.instructions().iter().find_map(|instruction| {
+        let is_pool_instruction = account_keys.get(instruction.program_id_index as usize) == Some(program_id)
+            && instruction.accounts.first().and_then(|index| account_keys.get(*index as usize)) == Some(pool_id);
+
+        if !is_pool_instruction || instruction.data.len() < 8 {
+            return None;
+        }
+
+        let kind = match &instruction.data[..8] {
+            discriminator if discriminator == DepositSol::DISCRIMINATOR => LpEventKind::Deposit,
+            discriminator if discriminator == WithdrawSol::DISCRIMINATOR => LpEventKind::WithdrawSol,
+            discriminator if discriminator == WithdrawStakeAccount::DISCRIMINATOR => LpEventKind::WithdrawStakeAccount,
+            _ => return None,
+        };
+
+        Some((kind, instruction))
+    }) else {
+        return Ok(None);
+    };
+
+    // deposit_sol, withdraw_sol and withdraw_stake_account all start with pool, sol_vault, lp_mint, user and
+    // user_lp_account
+    let instruction_account = |position: usize| instruction.accounts.get(position).map(|index| *index as usize);
+    let sol_vault_index = instruction_account(1);
+    let user_index = instruction_account(3);
+    let lp_index = instruction_account(4);
+
+    let lamports_delta = |index: usize| meta.post_balances[index] as i64 - meta.pre_balances[index] as i64;
+
+    let lp_tokens_delta = {
+        let token_amount = |balances: Option<Vec<UiTransactionTokenBalance>>| -> u64 {
+            balances
+                .unwrap_or_default()
+                .iter()
+                .find(|balance| Some(balance.account_index as usize) == lp_index)
+                .and_then(|balance| balance.ui_token_amount.amount.parse().ok())
+                .unwrap_or(0)
+        };
+
+        token_amount(meta.post_token_balances.into()) as i64 - token_amount(meta.pre_token_balances.into()) as i64
+    };
+
+    let lamports = match kind {
+        // The deposit lands in the SOL vault, the wallet may also have paid rent for its LP token account
+        LpEventKind::Deposit => sol_vault_index.map(lamports_delta).unwrap_or(0),
+        // The wallet receives the SOL after the withdraw fee, add back the transaction fee if it paid it
+        LpEventKind::WithdrawSol => user_index
+            .map(|index| lamports_delta(index) + if index == 0 { meta.fee as i64 } else { 0 })
+            .unwrap_or(0),
+        // The new stake account holds the stake received
+        LpEventKind::WithdrawStakeAccount => instruction_account(5)
+            .map(|index| meta.post_balances[index] as i64)
+            .unwrap_or(0),
+    };
+
+    Ok(Some(LpEvent {
+        signature: *signature,
+        slot: tx.slot,
+        block_time: tx.block_time,
+        kind,
+        lp_tokens: lp_tokens_delta.unsigned_abs(),
+        lamports: lamports.max(0) as u64,
+    }))
+}
+
+/// Function to summarize the LP position from its events using the average cost method. `value` is the current
+/// redemption value of the `lp_tokens` held now, `now` the current unix timestamp
+pub fn summarize_lp_position(events: &[LpEvent], lp_tokens: u64, value: u64, now: i64) -> LpPositionSummary {
+    let mut lp_tokens_held = 0u64;
+    let mut cost_basis = 0u64;
+    let mut realized_pnl = 0i64;
+
+    // Integral of the cost basis over time, for the time-weighted capital
+    let mut cost_basis_seconds = 0f64;
+    let mut last_time = None;
+    let first_time = events.iter().find_map(|event| event.block_time);
+
+    for event in events {
+        if let (Some(last_time), Some(block_time)) = (last_time, event.block_time) {
+            cost_basis_seconds += cost_basis as f64 * (block_time - last_time) as f64;
+        }
+        last_time = event.block_time.or(last_time);
+
+        match event.kind {
+            LpEventKind::Deposit => {
+                lp_tokens_held += event.lp_tokens;
+                cost_basis += event.lamports;
+            }
+            LpEventKind::WithdrawSol | LpEventKind::WithdrawStakeAccount => {
+                let cost_removed = if lp_tokens_held > 0 {
+                    (cost_basis as u128 * event.lp_tokens.min(lp_tokens_held) as u128 / lp_tokens_held as u128) as u64
+                } else {
+                    0
+                };
+
+                realized_pnl += event.lamports as i64 - cost_removed as i64;
+                cost_basis -= cost_removed;
+                lp_tokens_held = lp_tokens_held.saturating_sub(event.lp_tokens);
+            }
+        }
+    }
+
+    if let Some(last_time) = last_time {
+        cost_basis_seconds += cost_basis as f64 * (now - last_time) as f64;
+    }
+
+    // LP tokens received or sent outside of deposits and withdrawals are not part of the history, scale the cost
+    // basis to the LP tokens actually held
+    if lp_tokens_held > 0 && lp_tokens != lp_tokens_held {
+        cost_basis = (cost_basis as u128 * lp_tokens as u128 / lp_tokens_held as u128) as u64;
+    }
+
+    let unrealized_pnl = value as i64 - cost_basis as i64;
+    let holding_period_seconds = first_time.map(|first_time| (now - first_time).max(0) as f64).unwrap_or(0.0);
+
+    let annualized_yield_pct = (holding_period_seconds > 0.0 && cost_basis_seconds > 0.0).then(|| {
+        let average_capital = cost_basis_seconds / holding_period_seconds;
+
+        (realized_pnl + unrealized_pnl) as f64 / average_capital * SECONDS_PER_YEAR / holding_period_seconds * 100.0
+    });
+
+    LpPositionSummary {
+        lp_tokens,
+        cost_basis,
+        value,
+        unrealized_pnl,
+        realized_pnl,
+        holding_period_days: holding_period_seconds / (24.0 * 60.0 * 60.0),
+        annualized_yield_pct,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+
+    use super::*;
+
+    const HALF_YEAR: i64 = (SECONDS_PER_YEAR / 2.0) as i64;
+
+    fn get_event(block_time: i64, kind: LpEventKind, lp_tokens: u64, lamports: u64) -> LpEvent {
+        LpEvent {
+            signature: Signature::default(),
+            slot: 0,
+            block_time: Some(block_time),
+            kind,
+            lp_tokens: lp_tokens * LAMPORTS_PER_SOL,
+            lamports: lamports * LAMPORTS_PER_SOL,
+        }
+    }
+
+    #[test]
+    fn test_summarize_lp_position() {
+        // 100 SOL deposited, half of the LP tokens withdrawn for 55 SOL after half a year
+        let events = [
+            get_event(0, LpEventKind::Deposit, 100, 100),
+            get_event(HALF_YEAR, LpEventKind::WithdrawSol, 50, 55),
+        ];
+
+        let summary =
+            summarize_lp_position(&events, 50 * LAMPORTS_PER_SOL, 55 * LAMPORTS_PER_SOL, 2 * HALF_YEAR);
+
+        assert_eq!(summary.cost_basis, 50 * LAMPORTS_PER_SOL);
+        assert_eq!(summary.realized_pnl, 5 * LAMPORTS_PER_SOL as i64);
+        assert_eq!(summary.unrealized_pnl, 5 * LAMPORTS_PER_SOL as i64);
+        assert!((summary.holding_period_days - 365.25).abs() < 1e-6);
+
+        // 10 SOL earned over an average capital of 75 SOL in a year
+        assert!((summary.annualized_yield_pct.unwrap() - 10.0 / 75.0 * 100.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_summarize_lp_position_average_cost() {
+        // Deposits at two share prices, a withdrawal takes out the average cost
+        let events = [
+            get_event(0, LpEventKind::Deposit, 100, 100),
+            get_event(HALF_YEAR, LpEventKind::Deposit, 100, 120),
+            get_event(HALF_YEAR, LpEventKind::WithdrawStakeAccount, 100, 115),
+        ];
+
+        let summary =
+            summarize_lp_position(&events, 100 * LAMPORTS_PER_SOL, 115 * LAMPORTS_PER_SOL, 2 * HALF_YEAR);
+
+        assert_eq!(summary.cost_basis, 110 * LAMPORTS_PER_SOL);
+        assert_eq!(summary.realized_pnl, 5 * LAMPORTS_PER_SOL as i64);
+        assert_eq!(summary.unrealized_pnl, 5 * LAMPORTS_PER_SOL as i64);
+
+        // LP tokens sent away outside of the history scale the cost basis down
+        let summary = summarize_lp_position(&events, 50 * LAMPORTS_PER_SOL, 60 * LAMPORTS_PER_SOL, 2 * HALF_YEAR);
+
+        assert_eq!(summary.cost_basis, 55 * LAMPORTS_PER_SOL);
+        assert_eq!(summary.unrealized_pnl, 5 * LAMPORTS_PER_SOL as i64);
+    }
+
+    #[test]
+    fn test_summarize_lp_position_without_history() {
+        let summary = summarize_lp_position(&[], 0, 0, 0);
+
+        assert_eq!(summary.cost_basis, 0);
+        assert_eq!(summary.realized_pnl, 0);
+        assert_eq!(summary.holding_period_days, 0.0);
+        assert!(summary.annualized_yield_pct.is_none());
+    }
+}
@@ -9,16 +9,19 @@ use anchor_client::{
         rpc_filter::{Memcmp, RpcFilterType},
     },
     solana_sdk::{
-        self, pubkey::Pubkey, signature::{read_keypair_file, Keypair}, signer::Signer, system_instruction::create_account, transaction::Transaction
+        self, instruction::Instruction, pubkey::Pubkey, signature::{read_keypair_file, Keypair, Signature}, signer::Signer, system_instruction::create_account, transaction::Transaction
     },
     Client,
 };
 use anchor_lang::prelude::*;
 use anchor_spl::associated_token;
 use anchor_spl::{
-    token::spl_token, token_interface::spl_token_metadata_interface::borsh::BorshDeserialize,
+    token::spl_token::{self, solana_program::program_option::COption},
+    token_2022::spl_token_2022,
+    token_interface::spl_token_metadata_interface::borsh::BorshDeserialize,
 };
 use anyhow::Result;
+use base64::Engine;
 use clap::{Arg, Command};
 use fee::{Fee, FEE_PCT_DIVISOR};
 use itertools::{izip, Itertools};
@@ -30,6 +33,23 @@ use spl_stake_pool::{
 
 mod fee;
 mod error;
+mod portfolio;
+mod batch;
+mod token;
+mod config;
+mod pools;
+mod fee_curve;
+mod max_unstake;
+mod lp;
+mod lp_position;
+mod pool_apy;
+mod stake;
+mod route;
+mod order;
+mod twap;
+mod watch_quote;
+#[cfg(test)]
+mod test_utils;
 
 const SANCTUM_SINGLE_VALIDATOR_STAKE_POOL_PROGRAM: Pubkey =
     pubkey!("SP12tWFxD9oJsVWNavTTBZvMbA6gkAmxtVgxdqvyvhY");
@@ -56,6 +76,28 @@ impl PubkeyOrKeypair {
     }
 }
 
+/// Optional settings for building a liquid unstake transaction
+#[derive(Default)]
+struct UnstakeLstOptions<'a> {
+    /// Wallet receiving the SOL (or wSOL), defaults to the transfer authority
+    recipient: Option<Pubkey>,
+    minimum_lamports_out: Option<u64>,
+    /// LST token account to unstake from, defaults to the ATA of the transfer authority. If it is owned by
+    /// someone else the transfer authority has to be a delegate of the token account
+    source_lst_account: Option<Pubkey>,
+    /// Owner of the source LST token account, if set an approve of the transfer authority as delegate is
+    /// added to the transaction
+    approve_owner: Option<&'a Keypair>,
+    /// Close the recipient's wSOL account after a wrapped unstake so native SOL lands in the recipient wallet
+    unwrap: bool,
+    /// Close the source LST token account to reclaim its rent if the unstake empties it
+    close_empty_lst_account: bool,
+    /// Priority fee in micro-lamports per compute unit
+    compute_unit_price: Option<u64>,
+    /// Sets the minimum lamports out to the quote minus this slippage, unless the minimum is given explicitly
+    slippage_bps: Option<u16>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Define the CLI using clap
@@ -63,8 +105,27 @@ async fn main() -> Result<()> {
         .version("0.1")
         .arg(Arg::new("pool")
             .long("pool")
-            .help("The liquid unstake pool ID")
-            .required(true)
+            .help("The liquid unstake pool ID or name, e.g. main")
+            .required(false)
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .help("Config file, defaults to ~/.config/liquid-unstaker/config.toml")
+                .required(false)
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .help("Profile of the config file to use, defaults to the default profile")
+                .required(false)
+        )
+        .arg(
+            Arg::new("best-pool")
+                .long("best-pool")
+                .help("Quote the unstake against every unstake pool of the program and use the pool with the best output, instead of --pool (for the quote-unstake-lst, unstake-lst, quote-unstake-lst-wrapped and unstake-lst-wrapped commands)")
+                .action(clap::ArgAction::SetTrue)
+                .required(false)
         )
         .arg(
             Arg::new("simulate")
@@ -82,11 +143,97 @@ async fn main() -> Result<()> {
                 .action(clap::ArgAction::SetTrue)
                 .required(false)
         )
+        .arg(
+            Arg::new("recipient")
+                .long("recipient")
+                .help("Wallet receiving the SOL (or wSOL) of an unstake, defaults to the keypair wallet (for unstake-lst, unstake-lst-wrapped and unstake-all commands)")
+                .required(false)
+        )
+        .arg(
+            Arg::new("fee-payer")
+                .long("fee-payer")
+                .help("Keypair paying the transaction fees and rent of an unstake, defaults to the wallet keypair (for unstake-lst, unstake-lst-wrapped, unstake-all and batch commands)")
+                .required(false)
+        )
+        .arg(
+            Arg::new("authority")
+                .long("authority")
+                .help("Owner of the LST tokens to unstake, if set the transaction is only signed by the fee payer and printed as base64 for the owner to co-sign (for unstake-lst and unstake-lst-wrapped commands)")
+                .required(false)
+        )
+        .arg(
+            Arg::new("source-lst-account")
+                .long("source-lst-account")
+                .help("LST token account to unstake from, defaults to the ATA of the transfer authority (for unstake-lst and unstake-lst-wrapped commands)")
+                .required(false)
+        )
+        .arg(
+            Arg::new("delegate")
+                .long("delegate")
+                .help("Keypair of a token delegate of the source LST token account, used as transfer authority instead of the wallet (for unstake-lst and unstake-lst-wrapped commands)")
+                .conflicts_with("authority")
+                .required(false)
+        )
+        .arg(
+            Arg::new("approve-delegate")
+                .long("approve-delegate")
+                .help("Approve the delegate for the unstaked amount in the same transaction, the wallet has to be the owner of the source LST token account, which defaults to the ATA of the wallet")
+                .value_parser(clap::value_parser!(bool))
+                .action(clap::ArgAction::SetTrue)
+                .requires("delegate")
+                .required(false)
+        )
+        .arg(
+            Arg::new("unwrap")
+                .long("unwrap")
+                .help("Close the wSOL account after the unstake so native SOL lands in the recipient wallet, skipped when the account already held wSOL before the unstake (for the unstake-lst-wrapped command)")
+                .value_parser(clap::value_parser!(bool))
+                .action(clap::ArgAction::SetTrue)
+                .required(false)
+        )
+        .arg(
+            Arg::new("close-empty-lst-account")
+                .long("close-empty-lst-account")
+                .help("Close the LST token account to reclaim its rent if the unstake empties it (for unstake-lst, unstake-lst-wrapped and unstake-all commands)")
+                .value_parser(clap::value_parser!(bool))
+                .action(clap::ArgAction::SetTrue)
+                .required(false)
+        )
         .arg(
             Arg::new("rpc")
                 .long("rpc")
                 .help("The URL of the Solana RPC")
-                .required(true)
+                .required(false)
+        )
+        .arg(
+            Arg::new("program-id")
+                .long("program-id")
+                .help("The liquid unstaker program ID, for devnet or local fork deployments of the program")
+                .required(false)
+        )
+        .arg(
+            Arg::new("ws")
+                .long("ws")
+                .help("The websocket URL of the Solana RPC, derived from the RPC URL (http→ws, https→wss, port+1) by default")
+                .required(false)
+        )
+        .arg(
+            Arg::new("commitment")
+                .long("commitment")
+                .help("Commitment level, e.g. processed, confirmed or finalized")
+                .required(false)
+        )
+        .arg(
+            Arg::new("priority-fee")
+                .long("priority-fee")
+                .help("Priority fee in micro-lamports per compute unit for any transactions sent")
+                .required(false)
+        )
+        .arg(
+            Arg::new("slippage-bps")
+                .long("slippage-bps")
+                .help("Maximum slippage of an unstake against its quote in basis points, sets the minimum lamports out")
+                .required(false)
         )
         .arg(
             Arg::new("keypair")
@@ -105,10 +252,46 @@ async fn main() -> Result<()> {
         )
         .subcommand(
             Command::new("withdraw")
-                .about("Withdraw from the liquid unstake pool and receive SOL back")
+                .about("Withdraw from the liquid unstake pool and receive SOL back, and stake accounts if the SOL vault cannot cover the withdrawal")
                 .arg(
                     Arg::new("tokens")
                         .help("Amount of LP tokens to deposit in order to withdraw corresponding lamports from the pool")
+                        .value_parser(clap::value_parser!(u64))
+                )
+                .arg(
+                    Arg::new("sol")
+                        .long("sol")
+                        .help("Withdraw the LP tokens needed to receive this many lamports after the withdraw fee")
+                        .value_parser(clap::value_parser!(u64))
+                )
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .help("Withdraw all LP tokens of the wallet")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .group(
+                    clap::ArgGroup::new("withdraw-amount")
+                        .args(["tokens", "sol", "all"])
+                        .required(true)
+                ),
+        )
+        .subcommand(
+            Command::new("quote-deposit")
+                .about("Get a quote of the LP tokens received for depositing into the liquid unstake pool")
+                .arg(
+                    Arg::new("lamports")
+                        .help("Amount to deposit")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                ),
+        )
+        .subcommand(
+            Command::new("quote-withdraw")
+                .about("Get a quote of the lamports received for withdrawing LP tokens from the liquid unstake pool")
+                .arg(
+                    Arg::new("tokens")
+                        .help("Amount of LP tokens to withdraw")
                         .required(true)
                         .value_parser(clap::value_parser!(u64))
                 ),
@@ -130,7 +313,7 @@ async fn main() -> Result<()> {
         )
         .subcommand(
             Command::new("unstake-lst-wrapped")
-                .about("Unstake the LST from the pool and receive WSOL back (V2 only)")
+                .about("Unstake the LST from the pool and receive WSOL back (V2 only, LSTs of the SPL Token program only)")
                 .arg(
                     Arg::new("mint")
                         .help("Mint of the LST token")
@@ -183,37 +366,620 @@ async fn main() -> Result<()> {
                         .value_parser(clap::value_parser!(u64))
                 )
         )
+        .subcommand(
+            Command::new("list-pools")
+                .about("List all unstake pools deployed under the liquid unstaker program")
+        )
         .subcommand(
             Command::new("pool-info")
                 .about("Get information about the unstake pool")
+                .arg(
+                    Arg::new("raw")
+                        .long("raw")
+                        .help("Print the raw pool account instead")
+                        .action(clap::ArgAction::SetTrue)
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("max-unstake")
+                .about("Find the largest unstake of an LST within fee limits, the vault liquidity and the stake account limit of a transaction")
+                .arg(
+                    Arg::new("mint")
+                        .help("The LST mint")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("max-fee-pct")
+                        .long("max-fee-pct")
+                        .help("Maximum effective fee in percent, e.g. 0.3")
+                        .value_parser(clap::value_parser!(f64))
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("max-marginal-fee-pct")
+                        .long("max-marginal-fee-pct")
+                        .help("Maximum marginal fee in percent")
+                        .value_parser(clap::value_parser!(f64))
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("max-amount")
+                        .long("max-amount")
+                        .help("Maximum amount of LST tokens to unstake, e.g. the wallet balance")
+                        .value_parser(clap::value_parser!(u64))
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("fee-curve")
+                .about("Tabulate and chart the unstake fee against the unstake size at the current or a hypothetical vault level")
+                .arg(
+                    Arg::new("sol-vault-lamports")
+                        .long("sol-vault-lamports")
+                        .help("Hypothetical SOL vault balance in lamports, defaults to the current balance")
+                        .value_parser(clap::value_parser!(u64))
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("max-lamports")
+                        .long("max-lamports")
+                        .help("Largest unstake size in lamports, defaults to the whole vault")
+                        .value_parser(clap::value_parser!(u64))
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("steps")
+                        .long("steps")
+                        .help("Number of unstake sizes")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("40")
+                )
+                .arg(
+                    Arg::new("csv")
+                        .long("csv")
+                        .help("Print the table as CSV without the chart")
+                        .action(clap::ArgAction::SetTrue)
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("pool-apy")
+                .about("Record a snapshot of the pool and estimate the LP yield over the trailing 1, 7 and 30 epochs from the recorded snapshots")
+                .arg(
+                    Arg::new("store")
+                        .long("store")
+                        .help("Snapshot store, defaults to ~/.config/liquid-unstaker/snapshots/<pool>.jsonl")
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("no-record")
+                        .long("no-record")
+                        .help("Do not record a snapshot of the current pool state")
+                        .action(clap::ArgAction::SetTrue)
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("bootstrap")
+                        .long("bootstrap")
+                        .help("Reconstruct snapshots from the share prices of past deposits and withdrawals in the transaction history of the pool")
+                        .action(clap::ArgAction::SetTrue)
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("max-transactions")
+                        .long("max-transactions")
+                        .help("Maximum number of deposit and withdrawal transactions (those of the LP mint) to scan when bootstrapping")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10000")
+                )
+        )
+        .subcommand(
+            Command::new("lp-position")
+                .about("Reconstruct the deposits and withdrawals of a wallet and report the cost basis, value, PnL and yield of its LP position")
+                .arg(
+                    Arg::new("owner")
+                        .long("owner")
+                        .help("Wallet to report the LP position for, defaults to the keypair wallet")
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("unstake-stake")
+                .about("Liquid unstake a native stake account of the wallet and receive SOL back")
+                .arg(
+                    Arg::new("stake-account")
+                        .help("The stake account to unstake, the wallet must be its staker and withdrawer")
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("amount")
+                        .long("amount")
+                        .help("Only unstake this many lamports of stake by splitting them off into a new stake account first, the rest keeps earning")
+                        .value_parser(clap::value_parser!(u64))
+                        .conflicts_with("all")
+                        .required(false)
+                )
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .help("Unstake all stake accounts of the wallet, merging the ones with the same validator, activation state and authorities first")
+                        .action(clap::ArgAction::SetTrue)
+                )
+                .group(
+                    clap::ArgGroup::new("unstake-stake-accounts")
+                        .args(["stake-account", "all"])
+                        .required(true)
+                )
+        )
+        .subcommand(
+            Command::new("route")
+                .about("Compare liquid unstaking an LST with withdrawing SOL or stake from its stake pool, and execute the chosen route")
+                .arg(
+                    Arg::new("mint")
+                        .help("The LST mint")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("amount")
+                        .help("Amount of LST tokens")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                )
+                .arg(
+                    Arg::new("execute")
+                        .long("execute")
+                        .help("Execute this route: instant (liquid unstake), withdraw-sol (from the stake pool reserve) or delayed (withdraw stake and deactivate it, claim it next epoch with claim-stake)")
+                        .value_parser(["instant", "withdraw-sol", "delayed"])
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("claim-stake")
+                .about("Withdraw the lamports of the wallet's fully deactivated stake accounts, and report the ones still cooling down")
+        )
+        .subcommand(
+            Command::new("stake-accounts")
+                .about("List the stake accounts where the wallet is staker or withdrawer, with a liquid unstake quote for each")
+                .arg(
+                    Arg::new("owner")
+                        .long("owner")
+                        .help("Wallet to list the stake accounts for, defaults to the keypair wallet")
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("portfolio")
+                .about("List all supported LST token accounts of a wallet with their stake pool and instant unstake value")
+                .arg(
+                    Arg::new("owner")
+                        .long("owner")
+                        .help("Wallet to list the LST token accounts for, defaults to the keypair wallet")
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("batch")
+                .about("Unstake all rows (mint, amount, optional recipient, optional min_out) of a CSV or JSON file")
+                .arg(
+                    Arg::new("file")
+                        .help("CSV or JSON (.json extension) file with the unstakes to perform")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .help("File the per-row results are written to, an existing file is used to resume the batch")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .help("Maximum number of unstakes in flight")
+                        .default_value("4")
+                        .value_parser(clap::value_parser!(usize))
+                )
+        )
+        .subcommand(
+            Command::new("order")
+                .about("Conditional unstake orders that execute once the effective fee drops to a limit, kept in a local order book")
+                .subcommand_required(true)
+                .arg(
+                    Arg::new("orders")
+                        .long("orders")
+                        .help("Order book file, defaults to orders.json in the config directory")
+                        .global(true)
+                        .required(false)
+                )
+                .subcommand(
+                    Command::new("add")
+                        .about("Register an order to unstake an amount of an LST through the unstake pool once the effective fee is at most the limit")
+                        .arg(
+                            Arg::new("mint")
+                                .help("The LST mint")
+                                .required(true)
+                        )
+                        .arg(
+                            Arg::new("amount")
+                                .help("Amount of LST tokens to unstake")
+                                .required(true)
+                                .value_parser(clap::value_parser!(u64))
+                        )
+                        .arg(
+                            Arg::new("max-fee-bps")
+                                .long("max-fee-bps")
+                                .help("Maximum effective fee of the unstake pool in basis points")
+                                .required(true)
+                                .value_parser(clap::value_parser!(u32))
+                        )
+                        .arg(
+                            Arg::new("expires-in")
+                                .long("expires-in")
+                                .help("Seconds after which the order expires if it has not been filled")
+                                .required(false)
+                                .value_parser(clap::value_parser!(i64))
+                        )
+                )
+                .subcommand(
+                    Command::new("list")
+                        .about("List the orders of the order book")
+                )
+                .subcommand(
+                    Command::new("cancel")
+                        .about("Cancel an open order")
+                        .arg(
+                            Arg::new("id")
+                                .help("Order id")
+                                .required(true)
+                                .value_parser(clap::value_parser!(u64))
+                        )
+                )
+                .subcommand(
+                    Command::new("run")
+                        .about("Poll the unstake pools and execute the open orders whose fee limit is met, until stopped")
+                        .arg(
+                            Arg::new("interval")
+                                .long("interval")
+                                .help("Seconds between two polls")
+                                .default_value("30")
+                                .value_parser(clap::value_parser!(u64))
+                        )
+                        .arg(
+                            Arg::new("once")
+                                .long("once")
+                                .help("Poll once and exit, e.g. when run from cron")
+                                .action(clap::ArgAction::SetTrue)
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("twap-unstake")
+                .about("Unstake an amount of an LST in slices spread over a duration or a number of epochs, keeping the marginal fee of every slice under a cap")
+                .arg(
+                    Arg::new("mint")
+                        .help("The LST mint")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("amount")
+                        .help("Total amount of LST tokens to unstake")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                )
+                .arg(
+                    Arg::new("duration")
+                        .long("duration")
+                        .help("Seconds over which the amount is released linearly")
+                        .value_parser(clap::value_parser!(u64))
+                )
+                .arg(
+                    Arg::new("epochs")
+                        .long("epochs")
+                        .help("Number of epochs over which the amount is released in equal parts, starting with the current epoch")
+                        .value_parser(clap::value_parser!(u64))
+                )
+                .group(
+                    clap::ArgGroup::new("twap-schedule")
+                        .args(["duration", "epochs"])
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("max-marginal-fee-pct")
+                        .long("max-marginal-fee-pct")
+                        .help("Maximum marginal fee of every slice in percent, slices wait for the vault to refill otherwise")
+                        .value_parser(clap::value_parser!(f64))
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .help("Seconds between two slices")
+                        .default_value("60")
+                        .value_parser(clap::value_parser!(u64))
+                )
+                .arg(
+                    Arg::new("state")
+                        .long("state")
+                        .help("File the progress is kept in, an existing file resumes the TWAP unstake. Defaults to twap/<pool>-<mint>.json in the config directory")
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("watch-quote")
+                .about("Stream unstake quotes for the given amounts as JSON lines, recomputed on every change of the unstake pool, the stake pool and its validator list")
+                .arg(
+                    Arg::new("mint")
+                        .help("The LST mint")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("amounts")
+                        .help("Amounts of LST tokens to quote")
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(clap::value_parser!(u64))
+                )
+        )
+        .subcommand(
+            Command::new("unstake-all")
+                .about("Unstake all LSTs held by the wallet, or only the given mints, in the order with the lowest fees")
+                .arg(
+                    Arg::new("mints")
+                        .help("Only unstake these LST mints")
+                        .required(false)
+                        .num_args(0..)
+                )
         )
         .get_matches();
 
-    // Extract arguments
-    let rpc_url: &String = matches.get_one("rpc").unwrap();
-    let unstake_pool_id = Pubkey::from_str(matches.get_one::<String>("pool").unwrap()).unwrap();
+    // Extract arguments, falling back to the config file, environment variables and the Solana CLI config
+    let config = config::Config::load(&matches)?;
     let simulate = *matches.get_one::<bool>("simulate").unwrap_or(&false);
     let new_stake_account_as_pda = !*matches.get_one::<bool>("no-stake-account-as-pda").unwrap_or(&false);
+    let recipient = matches
+        .get_one::<String>("recipient")
+        .map(|recipient| Pubkey::from_str(recipient))
+        .transpose()?;
     let wallet_keypair = {
         
         // Load the wallet keypair file
-        let wallet_keypair_path: Option<&String> = matches.get_one("keypair");
+        let wallet_keypair_path = config.keypair_path.as_ref();
 
         if let Some(wallet_keypair_path) = wallet_keypair_path {
-            read_keypair_file(wallet_keypair_path)
-                .map_err(|_| anyhow::anyhow!("Failed to read wallet keypair file {}", wallet_keypair_path))
+            match read_keypair_file(wallet_keypair_path) {
+                Ok(wallet_keypair) => Ok(wallet_keypair),
+                // The keypair of the Solana CLI config is optional, e.g. for quotes
+                Err(_) if !config.keypair_required => Ok(Keypair::new()),
+                Err(_) => Err(anyhow::anyhow!("Failed to read wallet keypair file {}", wallet_keypair_path)),
+            }
         } else {
             Ok(Keypair::new())
         }
-    }?;
+    }?;
+    let fee_payer_keypair = matches
+        .get_one::<String>("fee-payer")
+        .map(|fee_payer_keypair_path| {
+            read_keypair_file(fee_payer_keypair_path)
+                .map_err(|_| anyhow::anyhow!("Failed to read fee payer keypair file {}", fee_payer_keypair_path))
+        })
+        .transpose()?;
+    let fee_payer = fee_payer_keypair.as_ref().unwrap_or(&wallet_keypair);
+    let delegate_keypair = matches
+        .get_one::<String>("delegate")
+        .map(|delegate_keypair_path| {
+            read_keypair_file(delegate_keypair_path)
+                .map_err(|_| anyhow::anyhow!("Failed to read delegate keypair file {}", delegate_keypair_path))
+        })
+        .transpose()?;
+    let authority = match (delegate_keypair, matches.get_one::<String>("authority")) {
+        (Some(delegate_keypair), _) => PubkeyOrKeypair::Keypair(delegate_keypair),
+        (None, Some(authority)) => PubkeyOrKeypair::Pubkey(Pubkey::from_str(authority)?),
+        (None, None) => PubkeyOrKeypair::Keypair(wallet_keypair.insecure_clone()),
+    };
+    let unstake_options = UnstakeLstOptions {
+        recipient,
+        source_lst_account: matches
+            .get_one::<String>("source-lst-account")
+            .map(|source_lst_account| Pubkey::from_str(source_lst_account))
+            .transpose()?,
+        approve_owner: matches
+            .get_flag("approve-delegate")
+            .then_some(&wallet_keypair),
+        unwrap: matches.get_flag("unwrap"),
+        close_empty_lst_account: matches.get_flag("close-empty-lst-account"),
+        compute_unit_price: config.priority_fee,
+        slippage_bps: config.slippage_bps,
+        ..Default::default()
+    };
+
+    // Set up the anchor client
+    let client = Client::new_with_options(
+        anchor_client::Cluster::Custom(config.rpc_url.clone(), config.websocket_url.clone()),
+        Rc::new(wallet_keypair.insecure_clone()),
+        config.commitment,
+    );
+    let program: anchor_client::Program<Rc<Keypair>> =
+        client.program(config.program_id)?;
+
+    // Commands that do not need an unstake pool
+    if let Some(("list-lst-mints", arg_matches)) = matches.subcommand() {
+        let limit = *arg_matches.get_one::<u64>("limit").unwrap_or(&u64::MAX);
+
+        let rpc = program.rpc();
+
+        let mut mints = vec![];
+
+        for program_id in SUPPORTED_STAKE_POOL_PROGRAMS {
+            let mints_for_program = get_stake_pool_mints(&rpc, &program_id).await?;
+            mints.extend(mints_for_program);
+        }
+
+        mints
+            .into_iter()
+            .take(limit as usize)
+            .for_each(|mint| println!("{:?}", mint));
+
+        return Ok(());
+    }
+
+    if let Some(("list-pools", _arg_matches)) = matches.subcommand() {
+        let pools = pools::get_unstake_pools(&program.rpc(), &program.id()).await?;
+
+        println!(
+            "{:<44} {:<44} {:>20} {:>20} {:>9} {:>9} {:>20} {:>8} {:>20} {:>12}",
+            "Pool", "Authority", "Liquidity", "Cap", "Fee min", "Fee max", "Min SOL for min fee", "Manager", "LP supply", "Flash loans"
+        );
+
+        for (pool_id, pool) in pools.iter() {
+            let flash_loans = match (pool.flash_loans_enabled, pool.flash_loan_borrowed_amount) {
+                (false, _) => "disabled".to_string(),
+                (true, 0) => "enabled".to_string(),
+                (true, borrowed) => format!("{} out", borrowed),
+            };
+
+            println!(
+                "{:<44} {:<44} {:>20} {:>20} {:>9} {:>9} {:>20} {:>8} {:>20} {:>12}",
+                pool_id.to_string(),
+                pool.authority.to_string(),
+                pool.sol_vault_lamports,
+                pool.sol_vault_lamports_cap,
+                format!("{:.3}%", pool.fee_min as f64 * 100.0 / FEE_PCT_DIVISOR as f64),
+                format!("{:.3}%", pool.fee_max as f64 * 100.0 / FEE_PCT_DIVISOR as f64),
+                pool.min_sol_for_min_fee,
+                format!("{}%", pool.manager_fee_pct),
+                pool.total_lp_tokens,
+                flash_loans,
+            );
+        }
+
+        println!("{} pools found", pools.len());
+
+        return Ok(());
+    }
+
+    if let Some(("claim-stake", _arg_matches)) = matches.subcommand() {
+        let rpc = program.rpc();
+        let withdrawer = wallet_keypair.pubkey();
+        let recipient = unstake_options.recipient.unwrap_or(withdrawer);
+
+        let stake_accounts = stake::get_wallet_stake_accounts(&rpc, &withdrawer)
+            .await?
+            .into_iter()
+            .filter(|stake_account| stake_account.meta.authorized.withdrawer == withdrawer)
+            .collect::<Vec<_>>();
+
+        let clock = stake::get_clock(&rpc).await?;
+        let stake_history = stake::get_stake_history(&rpc).await?;
+        let new_rate_activation_epoch = stake::get_new_rate_activation_epoch(&rpc).await?;
+
+        println!(
+            "{:<44} {:>20} {:<14} {:>16} {:>20}",
+            "Stake account", "Lamports", "Status", "Claimable epoch", "Cooling down"
+        );
+
+        let mut claimable = vec![];
+
+        for stake_account in stake_accounts.iter() {
+            let Some(stake) = stake_account.stake.as_ref() else {
+                continue;
+            };
+
+            let claim_status =
+                stake::get_claim_status(stake, &stake_history, clock.epoch, new_rate_activation_epoch);
+
+            let (status, claimable_epoch, cooling_down) = match claim_status {
+                stake::ClaimStatus::NotDeactivated => continue,
+                stake::ClaimStatus::CoolingDown {
+                    effective,
+                    claimable_epoch,
+                } => ("cooling down", claimable_epoch, effective),
+                _ if stake_account.meta.lockup.is_in_force(&clock, None) => ("lockup", clock.epoch, 0),
+                stake::ClaimStatus::Claimable => {
+                    claimable.push(stake_account);
+
+                    ("claimable", clock.epoch, 0)
+                }
+            };
+
+            println!(
+                "{:<44} {:>20} {:<14} {:>16} {:>20}",
+                stake_account.address.to_string(),
+                stake_account.lamports,
+                status,
+                claimable_epoch,
+                cooling_down,
+            );
+        }
+
+        if claimable.is_empty() {
+            println!("No stake accounts to claim");
+
+            return Ok(());
+        }
+
+        println!(
+            "Claiming {} lamports from {} stake accounts to {}",
+            claimable.iter().map(|stake_account| stake_account.lamports).sum::<u64>(),
+            claimable.len(),
+            recipient
+        );
+
+        let instructions = claimable
+            .iter()
+            .map(|stake_account| stake::get_claim_stake_instruction(stake_account, &withdrawer, &recipient))
+            .collect_vec();
+        let prefix = unstake_options
+            .compute_unit_price
+            .map(|compute_unit_price| {
+                vec![solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price)]
+            })
+            .unwrap_or_default();
+        let transactions = stake::pack_instructions_into_transactions(instructions, &prefix, &fee_payer.pubkey())?;
+
+        let mut signers = vec![fee_payer];
+        if withdrawer != fee_payer.pubkey() {
+            signers.push(&wallet_keypair);
+        }
+
+        // Every transaction claims its own stake accounts, so a failed one does not stop the others
+        let mut failed = vec![];
+
+        for (index, instructions) in transactions.iter().enumerate() {
+            println!("Transaction {}/{}", index + 1, transactions.len());
+
+            let recent_blockhash = rpc.get_latest_blockhash().await?;
+
+            let tx = Transaction::new_signed_with_payer(
+                instructions,
+                Some(&fee_payer.pubkey()),
+                &signers,
+                recent_blockhash,
+            );
+
+            if let Err(err) = send_or_simulate_transaction_checked(&rpc, &tx, simulate, Some(vec![recipient])).await {
+                println!("{}", err);
+                failed.push(index + 1);
+            }
+        }
 
-    // Set up the anchor client
-    let client = Client::new(
-        anchor_client::Cluster::Custom(rpc_url.clone(), rpc_url.clone()),
-        Rc::new(wallet_keypair.insecure_clone()),
-    );
-    let program: anchor_client::Program<Rc<Keypair>> =
-        client.program(liquid_unstaker::liquid_unstaker::ID_CONST)?;
+        if !failed.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Transactions {} of {} failed, their stake accounts were not claimed",
+                failed.iter().join(", "),
+                transactions.len()
+            ));
+        }
+
+        return Ok(());
+    }
+
+    let unstake_pool_id = if matches.get_flag("best-pool") {
+        get_best_pool_for_unstake(&program, &matches, new_stake_account_as_pda).await?
+    } else {
+        config.pool.ok_or(anyhow::anyhow!(
+            "No unstake pool given, use --pool or set the pool of the config profile"
+        ))?
+    };
 
     // Load unstake pool info
     let unstake_pool_info = program
@@ -265,6 +1031,8 @@ async fn main() -> Result<()> {
 
                 let in_amount = *arg_matches.get_one::<u64>("amount").unwrap();
 
+                check_wrapped_unstake_token_program(&spl_stake_pool_state)?;
+
                 let (quote_wsol, fees) =
                     quote_lst_unstake_wrapped(&spl_stake_pool_state, 
                         &unstake_pool_info, 
@@ -298,13 +1066,15 @@ async fn main() -> Result<()> {
                 unstake_lst(
                     &program,
                     &unstake_pool_id,
-                    &wallet_keypair,
+                    fee_payer,
+                    &authority,
                     &spl_stake_pool_program_id,
                     &mint,
                     &unstake_pool_info,
                     *amount,
                     simulate,
                     new_stake_account_as_pda,
+                    &unstake_options,
                 )
                 .await?;
             } else {
@@ -330,13 +1100,15 @@ async fn main() -> Result<()> {
                 unstake_lst_wrapped(
                     &program,
                     &unstake_pool_id,
-                    &wallet_keypair,
+                    fee_payer,
+                    &authority,
                     &spl_stake_pool_program_id,
                     &mint,
                     &unstake_pool_info,
                     *amount,
                     simulate,
                     new_stake_account_as_pda,
+                    &unstake_options,
                 )
                 .await?;
             } else {
@@ -345,22 +1117,49 @@ async fn main() -> Result<()> {
                 ));
             }
         }
+        Some(("quote-deposit", arg_matches)) => {
+            let lamports = *arg_matches.get_one::<u64>("lamports").unwrap();
+
+            let lp_tokens = lp::quote_deposit(&unstake_pool_info, lamports)?;
+
+            println!("Quote: {} LP tokens minted for {} lamports", lp_tokens, lamports);
+        }
+        Some(("quote-withdraw", arg_matches)) => {
+            let tokens = *arg_matches.get_one::<u64>("tokens").unwrap();
+
+            let quote = lp::quote_withdraw_sol(&unstake_pool_info, tokens)?;
+
+            println!(
+                "Quote: {} lamports received for {} LP tokens ({} lamports value, {} lamports withdraw fee)",
+                quote.lamports_out, quote.lp_tokens, quote.lamports, quote.withdraw_fee
+            );
+
+            if !quote.sufficient_liquidity {
+                println!(
+                    "Warning: the SOL vault only holds {} lamports, the rest of the pool value is deactivating stake",
+                    unstake_pool_info.sol_vault_lamports
+                );
+            }
+        }
         Some(("deposit", arg_matches)) => {
             // Get ATA for the LP token of the unstake pool
-            let user_unstake_pool_lp_ata = associated_token::get_associated_token_address(
+            let lp_token_program_id =
+                token::get_token_program_for_mint(&program.rpc(), &unstake_pool_info.lp_mint).await?;
+            let user_unstake_pool_lp_ata = associated_token::get_associated_token_address_with_program_id(
                 &wallet_keypair.pubkey(),
                 &unstake_pool_info.lp_mint,
+                &lp_token_program_id,
             );
 
             let lamports = *arg_matches.get_one::<u64>("lamports").unwrap();
 
-            let instructions = program
+            let mut instructions = program
                 .request()
                 .accounts(
                     liquid_unstaker::liquid_unstaker::client::accounts::DepositSol {
                         pool: unstake_pool_id,
                         sol_vault: unstake_pool_info.sol_vault,
-                        token_program: spl_token::id(),
+                        token_program: lp_token_program_id,
                         system_program: solana_sdk::system_program::id(),
                         lp_mint: unstake_pool_info.lp_mint,
                         user: wallet_keypair.pubkey(),
@@ -373,6 +1172,10 @@ async fn main() -> Result<()> {
                 })
                 .instructions()?;
 
+            if let Some(compute_unit_price) = unstake_options.compute_unit_price {
+                instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price));
+            }
+
             // Build transaction
             let recent_blockhash = program.rpc().get_latest_blockhash().await?;
 
@@ -387,67 +1190,1223 @@ async fn main() -> Result<()> {
             send_or_simulate_transaction(&program.rpc(), &tx, simulate, None).await?;
         }
         Some(("withdraw", arg_matches)) => {
+            let rpc = program.rpc();
+
             // Get ATA for the LP token of the unstake pool
-            let user_unstake_pool_lp_ata = associated_token::get_associated_token_address(
+            let lp_token_program_id =
+                token::get_token_program_for_mint(&rpc, &unstake_pool_info.lp_mint).await?;
+            let user_unstake_pool_lp_ata = associated_token::get_associated_token_address_with_program_id(
                 &wallet_keypair.pubkey(),
                 &unstake_pool_info.lp_mint,
+                &lp_token_program_id,
             );
 
-            let tokens = *arg_matches.get_one::<u64>("tokens").unwrap();
+            let tokens = if let Some(lamports_out) = arg_matches.get_one::<u64>("sol") {
+                lp::get_lp_tokens_for_withdraw_sol(&unstake_pool_info, *lamports_out)?
+            } else if arg_matches.get_flag("all") {
+                rpc.get_token_account_balance(&user_unstake_pool_lp_ata)
+                    .await?
+                    .amount
+                    .parse::<u64>()?
+            } else {
+                *arg_matches.get_one::<u64>("tokens").unwrap()
+            };
 
-            let instructions = program
-                .request()
-                .accounts(
-                    liquid_unstaker::liquid_unstaker::client::accounts::WithdrawSol {
-                        pool: unstake_pool_id,
-                        sol_vault: unstake_pool_info.sol_vault,
-                        token_program: spl_token::id(),
-                        system_program: solana_sdk::system_program::id(),
-                        lp_mint: unstake_pool_info.lp_mint,
-                        user: wallet_keypair.pubkey(),
-                        user_lp_account: user_unstake_pool_lp_ata,
-                    },
-                )
-                .args(
-                    liquid_unstaker::liquid_unstaker::client::args::WithdrawSol {
-                        lp_tokens: tokens,
+            if tokens == 0 {
+                return Err(anyhow::anyhow!("No LP tokens to withdraw"));
+            }
+
+            // Take whatever the SOL vault cannot cover as stake
+            let stake_accounts = if lp::get_lp_tokens_value(&unstake_pool_info, tokens)? > unstake_pool_info.sol_vault_lamports {
+                pools::get_pool_stake_accounts(&rpc, &program.id(), &unstake_pool_id)
+                    .await?
+                    .into_iter()
+                    .map(|(_, stake_account_info)| (stake_account_info.stake_account, stake_account_info.stake_lamports))
+                    .collect_vec()
+            } else {
+                vec![]
+            };
+
+            let plan = lp::plan_withdraw(&unstake_pool_info, tokens, &stake_accounts)?;
+
+            println!("Withdrawing {} LP tokens", tokens);
+
+            if plan.sol_lp_tokens > 0 {
+                let quote = lp::quote_withdraw_sol(&unstake_pool_info, plan.sol_lp_tokens)?;
+
+                println!(
+                    "Withdrawing {} LP tokens as {} lamports ({} lamports withdraw fee)",
+                    plan.sol_lp_tokens, quote.lamports_out, quote.withdraw_fee
+                );
+
+                let mut instructions = program
+                    .request()
+                    .accounts(
+                        liquid_unstaker::liquid_unstaker::client::accounts::WithdrawSol {
+                            pool: unstake_pool_id,
+                            sol_vault: unstake_pool_info.sol_vault,
+                            token_program: lp_token_program_id,
+                            system_program: solana_sdk::system_program::id(),
+                            lp_mint: unstake_pool_info.lp_mint,
+                            user: wallet_keypair.pubkey(),
+                            user_lp_account: user_unstake_pool_lp_ata,
+                        },
+                    )
+                    .args(
+                        liquid_unstaker::liquid_unstaker::client::args::WithdrawSol {
+                            lp_tokens: plan.sol_lp_tokens,
+                        },
+                    )
+                    .instructions()?;
+
+                if let Some(compute_unit_price) = unstake_options.compute_unit_price {
+                    instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price));
+                }
+
+                // Build transaction
+                let recent_blockhash = rpc.get_latest_blockhash().await?;
+
+                let tx = Transaction::new_signed_with_payer(
+                    &instructions,
+                    Some(&wallet_keypair.pubkey()),
+                    &[&wallet_keypair],
+                    recent_blockhash,
+                );
+
+                // Send or simulate the transaction, the stake withdrawals are only sent once it landed
+                send_or_simulate_transaction_checked(&rpc, &tx, simulate, None).await?;
+            }
+
+            for withdrawal in plan.stake_withdrawals {
+                let stake_account = withdrawal.stake_account;
+
+                println!(
+                    "Withdrawing {} LP tokens as {} lamports of stake ({} lamports withdraw fee) from stake account {}",
+                    withdrawal.lp_tokens, withdrawal.stake_lamports, withdrawal.withdraw_fee, stake_account
+                );
+
+                let stake_account_destination = Keypair::new();
+                let (stake_account_info_source, _) = Pubkey::find_program_address(
+                    &[b"stake_account_info", stake_account.as_ref()],
+                    &program.id(),
+                );
+
+                let mut instructions = program
+                    .request()
+                    .accounts(
+                        liquid_unstaker::liquid_unstaker::client::accounts::WithdrawStakeAccount {
+                            pool: unstake_pool_id,
+                            sol_vault: unstake_pool_info.sol_vault,
+                            lp_mint: unstake_pool_info.lp_mint,
+                            user: wallet_keypair.pubkey(),
+                            user_lp_account: user_unstake_pool_lp_ata,
+                            stake_account_destination: stake_account_destination.pubkey(),
+                            stake_account_source: stake_account,
+                            stake_account_info_source,
+                            system_program: solana_sdk::system_program::id(),
+                            token_program: lp_token_program_id,
+                            stake_program: solana_sdk::stake::program::id(),
+                            clock: solana_sdk::sysvar::clock::id(),
+                        },
+                    )
+                    .args(
+                        liquid_unstaker::liquid_unstaker::client::args::WithdrawStakeAccount {
+                            lp_tokens: withdrawal.lp_tokens,
+                        },
+                    )
+                    .instructions()?;
+
+                if let Some(compute_unit_price) = unstake_options.compute_unit_price {
+                    instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price));
+                }
+
+                let recent_blockhash = rpc.get_latest_blockhash().await?;
+
+                let tx = Transaction::new_signed_with_payer(
+                    &instructions,
+                    Some(&wallet_keypair.pubkey()),
+                    &[&wallet_keypair, &stake_account_destination],
+                    recent_blockhash,
+                );
+
+                // Stop at the first failed withdrawal, the LP tokens it would have burned stay in the wallet
+                if send_or_simulate_transaction_checked(&rpc, &tx, simulate, None).await?.is_some() {
+                    println!("New stake account: {}", stake_account_destination.pubkey());
+                }
+            }
+        }
+        Some(("pool-info", arg_matches)) => {
+            if arg_matches.get_flag("raw") {
+                println!("{:#?}", unstake_pool_info);
+            } else {
+                pools::print_pool_info(&program.rpc(), &unstake_pool_id, &unstake_pool_info).await?;
+            }
+        }
+        Some(("max-unstake", arg_matches)) => {
+            let rpc = program.rpc();
+            let mint = Pubkey::from_str(arg_matches.get_one::<String>("mint").unwrap())?;
+
+            let spl_stake_pool_program_id = get_stake_pool_program_for_lst_mint(&rpc, &mint)
+                .await?
+                .ok_or(anyhow::anyhow!("Could not find a supported stake pool for the given mint"))?;
+            let (_, spl_stake_pool_state) =
+                get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
+            let spl_stake_pool_validator_list = rpc
+                .get_account(&spl_stake_pool_state.validator_list)
+                .await
+                .map(|account| {
+                    let mut data = account.data.as_slice();
+                    spl_stake_pool::state::ValidatorList::deserialize(&mut data)
+                })??;
+
+            let quote = max_unstake::find_max_unstake(
+                &spl_stake_pool_state,
+                &spl_stake_pool_validator_list,
+                &unstake_pool_info,
+                &max_unstake::MaxUnstakeConstraints {
+                    max_effective_fee_pct: arg_matches.get_one::<f64>("max-fee-pct").copied(),
+                    max_marginal_fee_pct: arg_matches.get_one::<f64>("max-marginal-fee-pct").copied(),
+                    max_pool_tokens: arg_matches.get_one::<u64>("max-amount").copied(),
+                },
+            )?;
+
+            println!("Max unstake:          {} {:?} tokens", quote.pool_tokens, mint);
+            println!("Stake pool fee:       {} tokens", quote.stake_pool_fee_tokens);
+            println!("Lamports unstaked:    {} (including stake account rent)", quote.lamports_unstaked);
+            println!("Base fee:             {} lamports", quote.fee.base_fee);
+            println!("Manager fee:          {} lamports", quote.fee.manager_fee);
+            println!("Effective fee:        {:.4}%", quote.effective_fee_pct);
+            println!("Marginal fee:         {:.4}%", quote.marginal_fee_pct);
+            println!("Lamports out:         {}", quote.lamports_out);
+        }
+        Some(("fee-curve", arg_matches)) => {
+            let sol_vault_lamports = arg_matches
+                .get_one::<u64>("sol-vault-lamports")
+                .copied()
+                .unwrap_or(unstake_pool_info.sol_vault_lamports);
+            let max_lamports = arg_matches.get_one::<u64>("max-lamports").copied().unwrap_or(sol_vault_lamports);
+            let steps = *arg_matches.get_one::<u64>("steps").unwrap();
+
+            let points = fee_curve::get_fee_curve(&unstake_pool_info, sol_vault_lamports, max_lamports, steps)?;
+
+            if arg_matches.get_flag("csv") {
+                println!("unstake_lamports,effective_fee_pct,marginal_fee_pct,net_lamports_out");
+
+                for point in points.iter() {
+                    println!(
+                        "{},{:.6},{:.6},{}",
+                        point.unstake_lamports, point.effective_fee_pct, point.marginal_fee_pct, point.net_lamports_out
+                    );
+                }
+            } else {
+                println!("Fee curve for a SOL vault of {} lamports", sol_vault_lamports);
+                println!();
+                println!("{:>20} {:>14} {:>14} {:>20}", "Unstake lamports", "Effective fee", "Marginal fee", "Net out");
+
+                for point in points.iter() {
+                    println!(
+                        "{:>20} {:>13.4}% {:>13.4}% {:>20}",
+                        point.unstake_lamports, point.effective_fee_pct, point.marginal_fee_pct, point.net_lamports_out
+                    );
+                }
+
+                println!();
+                print!("{}", fee_curve::render_fee_curve_chart(&points));
+            }
+        }
+        Some(("pool-apy", arg_matches)) => {
+            let rpc = program.rpc();
+            let store = match arg_matches.get_one::<String>("store") {
+                Some(store) => std::path::PathBuf::from(store),
+                None => config::get_config_dir()
+                    .ok_or(anyhow::anyhow!("No home directory, pass --store"))?
+                    .join("snapshots")
+                    .join(format!("{}.jsonl", unstake_pool_id)),
+            };
+
+            if arg_matches.get_flag("bootstrap") {
+                let max_transactions = *arg_matches.get_one::<usize>("max-transactions").unwrap();
+                let snapshots =
+                    pool_apy::bootstrap_pool_snapshots(&rpc, &program.id(), &unstake_pool_id, &unstake_pool_info, max_transactions)
+                        .await?;
+                let appended = pool_apy::append_pool_snapshots(&store, &snapshots)?;
+
+                println!("Bootstrapped {} snapshots from the transaction history", appended);
+            }
+
+            if !arg_matches.get_flag("no-record") {
+                let snapshot = pool_apy::take_pool_snapshot(&rpc, &unstake_pool_info).await?;
+                pool_apy::append_pool_snapshots(&store, &[snapshot])?;
+            }
+
+            let snapshots = pool_apy::read_pool_snapshots(&store)?;
+
+            println!("{} snapshots in {}", snapshots.len(), store.display());
+            println!();
+            println!("{:>8} {:>12} {:>12} {:>14} {:>12} {:>20} {:>20}", "Epochs", "From epoch", "To epoch", "Return", "APY", "Fees accrued", "Rewards withdrawn");
+
+            for epochs in [1, 7, 30] {
+                let Some(trailing_return) = pool_apy::get_trailing_return(&snapshots, epochs) else {
+                    println!("{:>8} {:>12}", epochs, "not enough snapshots");
+                    continue;
+                };
+
+                let delta = |from: Option<u64>, to: Option<u64>| match (from, to) {
+                    (Some(from), Some(to)) => to.saturating_sub(from).to_string(),
+                    _ => "-".to_string(),
+                };
+
+                println!(
+                    "{:>8} {:>12} {:>12} {:>13.4}% {:>11.2}% {:>20} {:>20}",
+                    trailing_return.epochs,
+                    trailing_return.from.epoch,
+                    trailing_return.to.epoch,
+                    trailing_return.return_pct,
+                    trailing_return.apy_pct,
+                    delta(trailing_return.from.total_accrued_fees, trailing_return.to.total_accrued_fees),
+                    delta(
+                        trailing_return.from.total_stake_rewards_withdrawn,
+                        trailing_return.to.total_stake_rewards_withdrawn
+                    ),
+                );
+            }
+
+            if let Some(latest) = snapshots.last() {
+                println!();
+                println!(
+                    "Latest LP share price: {:.9} lamports per LP token in epoch {}{}",
+                    latest.lp_share_price,
+                    latest.epoch,
+                    latest
+                        .last_stake_rewards_withdrawn_epoch
+                        .map(|epoch| format!(", stake rewards last withdrawn in epoch {}", epoch))
+                        .unwrap_or_default()
+                );
+            }
+        }
+        Some(("lp-position", arg_matches)) => {
+            let rpc = program.rpc();
+            let owner = match arg_matches.get_one::<String>("owner") {
+                Some(owner) => Pubkey::from_str(owner)?,
+                None => wallet_keypair.pubkey(),
+            };
+
+            let lp_token_program_id = token::get_token_program_for_mint(&rpc, &unstake_pool_info.lp_mint).await?;
+            let user_unstake_pool_lp_ata = associated_token::get_associated_token_address_with_program_id(
+                &owner,
+                &unstake_pool_info.lp_mint,
+                &lp_token_program_id,
+            );
+
+            let events =
+                lp_position::get_lp_events(&rpc, &program.id(), &unstake_pool_id, &user_unstake_pool_lp_ata, None)
+                    .await?;
+
+            let lp_tokens = match rpc.get_token_account_balance(&user_unstake_pool_lp_ata).await {
+                Ok(balance) => balance.amount.parse::<u64>()?,
+                Err(_) => 0,
+            };
+            let value = lp::get_lp_tokens_value(&unstake_pool_info, lp_tokens)?;
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs() as i64;
+
+            println!("{:<20} {:<22} {:>20} {:>20} {:<88}", "Time", "Event", "LP tokens", "Lamports", "Signature");
+
+            for event in events.iter() {
+                println!(
+                    "{:<20} {:<22} {:>20} {:>20} {:<88}",
+                    event
+                        .block_time
+                        .and_then(|block_time| chrono::DateTime::from_timestamp(block_time, 0))
+                        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                        .unwrap_or("-".to_string()),
+                    format!("{:?}", event.kind),
+                    event.lp_tokens,
+                    event.lamports,
+                    event.signature.to_string(),
+                );
+            }
+
+            let summary = lp_position::summarize_lp_position(&events, lp_tokens, value, now);
+            let sol = |lamports: i64| lamports as f64 / solana_sdk::native_token::LAMPORTS_PER_SOL as f64;
+
+            println!();
+            println!("LP tokens:         {}", summary.lp_tokens);
+            println!("Cost basis:        {:.9} SOL", sol(summary.cost_basis as i64));
+            println!("Redemption value:  {:.9} SOL", sol(summary.value as i64));
+            println!("Unrealized PnL:    {:.9} SOL", sol(summary.unrealized_pnl));
+            println!("Realized PnL:      {:.9} SOL", sol(summary.realized_pnl));
+            println!("Holding period:    {:.1} days", summary.holding_period_days);
+            println!(
+                "Annualized yield:  {}",
+                summary
+                    .annualized_yield_pct
+                    .map(|yield_pct| format!("{:.2}%", yield_pct))
+                    .unwrap_or("-".to_string())
+            );
+        }
+        Some(("unstake-stake", arg_matches)) if arg_matches.get_flag("all") => {
+            let rpc = program.rpc();
+            let authority = wallet_keypair.pubkey();
+            let recipient = unstake_options.recipient.unwrap_or(authority);
+
+            let stake_accounts = stake::get_wallet_stake_accounts(&rpc, &authority)
+                .await?
+                .into_iter()
+                .filter(|stake_account| {
+                    stake_account.meta.authorized.staker == authority && stake_account.meta.authorized.withdrawer == authority
+                })
+                .collect::<Vec<_>>();
+
+            let clock = stake::get_clock(&rpc).await?;
+            let groups = stake::group_stake_accounts(&stake_accounts, &clock);
+
+            if groups.is_empty() {
+                return Err(anyhow::anyhow!("No stake accounts of the wallet can be unstaked"));
+            }
+
+            println!(
+                "{:<6} {:<44} {:<12} {:>8} {:>20} {:>20}",
+                "Group", "Validator", "Status", "Accounts", "Lamports", "Unstake quote"
+            );
+
+            // Every unstake takes its SOL out of the vault, so each group is quoted against the vault the previous
+            // groups leave behind
+            let mut pool_after = unstake_pool_info;
+            let mut instructions = vec![];
+            let mut total_quote = 0;
+
+            for (index, group) in groups.iter().enumerate() {
+                let (quote, fee) = stake::quote_stake_account_unstake(&pool_after, group.lamports)?;
+                // The manager fee is paid out of the vault as well
+                pool_after.sol_vault_lamports -= quote as u64 + fee.manager_fee;
+                total_quote += quote;
+
+                println!(
+                    "{:<6} {:<44} {:<12} {:>8} {:>20} {:>20}",
+                    index + 1,
+                    group.voter.to_string(),
+                    format!("{:?}", group.status),
+                    group.stake_accounts.len(),
+                    group.lamports,
+                    quote,
+                );
+
+                let minimum_lamports_out = unstake_options
+                    .slippage_bps
+                    .map(|slippage_bps| apply_slippage(quote, slippage_bps));
+
+                instructions.extend(stake::get_merge_stake_instructions(group, &authority));
+                instructions.extend(stake::get_unstake_stake_account_instructions(
+                    &program,
+                    &unstake_pool_id,
+                    &unstake_pool_info,
+                    &authority,
+                    &group.stake_accounts[0],
+                    &recipient,
+                    minimum_lamports_out,
+                )?);
+            }
+
+            println!(
+                "Total: {} lamports received for {} lamports of stake in {} stake accounts",
+                total_quote,
+                groups.iter().map(|group| group.lamports).sum::<u64>(),
+                groups.iter().map(|group| group.stake_accounts.len()).sum::<usize>()
+            );
+
+            let prefix = unstake_options
+                .compute_unit_price
+                .map(|compute_unit_price| {
+                    vec![solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price)]
+                })
+                .unwrap_or_default();
+            let transactions = stake::pack_instructions_into_transactions(instructions, &prefix, &fee_payer.pubkey())?;
+
+            let mut signers = vec![fee_payer];
+            if authority != fee_payer.pubkey() {
+                signers.push(&wallet_keypair);
+            }
+
+            // Merges and unstakes are sent in order, a group can span two transactions
+            for (index, instructions) in transactions.iter().enumerate() {
+                println!("Transaction {}/{}", index + 1, transactions.len());
+
+                let recent_blockhash = rpc.get_latest_blockhash().await?;
+
+                let tx = Transaction::new_signed_with_payer(
+                    instructions,
+                    Some(&fee_payer.pubkey()),
+                    &signers,
+                    recent_blockhash,
+                );
+
+                send_or_simulate_transaction_checked(&rpc, &tx, simulate, Some(vec![recipient])).await?;
+            }
+        }
+        Some(("unstake-stake", arg_matches)) => {
+            let rpc = program.rpc();
+            let stake_account_address = Pubkey::from_str(arg_matches.get_one::<String>("stake-account").unwrap())?;
+            let stake_account = stake::get_stake_account(&rpc, &stake_account_address).await?;
+
+            let clock = stake::get_clock(&rpc).await?;
+            let issues = stake::get_unstake_stake_account_issues(&stake_account, &clock);
+
+            if !issues.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Stake account {} cannot be unstaked: {}",
+                    stake_account_address,
+                    issues.join(", ")
+                ));
+            }
+
+            let recipient = unstake_options.recipient.unwrap_or(wallet_keypair.pubkey());
+            let split_stake_account = Keypair::new();
+            let mut instructions = vec![];
+
+            // Split the requested stake off into a new stake account and unstake that one instead
+            let (unstake_stake_account, unstake_lamports) = match arg_matches.get_one::<u64>("amount") {
+                Some(amount) => {
+                    let minimum_delegation = rpc.get_stake_minimum_delegation().await?;
+                    stake::check_split_amount(&stake_account, *amount, minimum_delegation)?;
+
+                    instructions.extend(stake::get_split_stake_instructions(
+                        &fee_payer.pubkey(),
+                        &wallet_keypair.pubkey(),
+                        &stake_account_address,
+                        *amount,
+                        &split_stake_account.pubkey(),
+                        stake_account.meta.rent_exempt_reserve,
+                    ));
+
+                    println!(
+                        "Splitting {} lamports of stake off {} into new stake account {}",
+                        amount,
+                        stake_account_address,
+                        split_stake_account.pubkey()
+                    );
+
+                    (split_stake_account.pubkey(), amount + stake_account.meta.rent_exempt_reserve)
+                }
+                None => (stake_account_address, stake_account.lamports),
+            };
+
+            let (quote, _) = stake::quote_stake_account_unstake(&unstake_pool_info, unstake_lamports)?;
+            let minimum_lamports_out = unstake_options
+                .slippage_bps
+                .map(|slippage_bps| apply_slippage(quote, slippage_bps));
+
+            println!("Quote: {} lamports received for {} lamports of stake", quote, unstake_lamports);
+
+            instructions.extend(stake::get_unstake_stake_account_instructions(
+                &program,
+                &unstake_pool_id,
+                &unstake_pool_info,
+                &wallet_keypair.pubkey(),
+                &unstake_stake_account,
+                &recipient,
+                minimum_lamports_out,
+            )?);
+
+            if let Some(compute_unit_price) = unstake_options.compute_unit_price {
+                instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price));
+            }
+
+            let mut signers = vec![fee_payer];
+            if wallet_keypair.pubkey() != fee_payer.pubkey() {
+                signers.push(&wallet_keypair);
+            }
+            if unstake_stake_account == split_stake_account.pubkey() {
+                signers.push(&split_stake_account);
+            }
+
+            let recent_blockhash = rpc.get_latest_blockhash().await?;
+
+            let tx = Transaction::new_signed_with_payer(
+                &instructions,
+                Some(&fee_payer.pubkey()),
+                &signers,
+                recent_blockhash,
+            );
+
+            send_or_simulate_transaction(&rpc, &tx, simulate, Some(vec![recipient])).await?;
+        }
+        Some(("route", arg_matches)) => {
+            let rpc = program.rpc();
+            let mint = Pubkey::from_str(arg_matches.get_one::<String>("mint").unwrap())?;
+            let amount = *arg_matches.get_one::<u64>("amount").unwrap();
+
+            let spl_stake_pool_program_id = get_stake_pool_program_for_lst_mint(&rpc, &mint)
+                .await?
+                .ok_or(anyhow::anyhow!("Could not find a supported stake pool for the given mint"))?;
+            let (spl_stake_pool_address, spl_stake_pool_state) =
+                get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
+            let spl_stake_pool_validator_list = rpc
+                .get_account(&spl_stake_pool_state.validator_list)
+                .await
+                .map(|account| {
+                    let mut data = account.data.as_slice();
+                    spl_stake_pool::state::ValidatorList::deserialize(&mut data)
+                })??;
+            let (reserve_lamports, reserve_rent_exempt_reserve) =
+                route::get_reserve_stake_balance(&rpc, &spl_stake_pool_state).await?;
+            let epoch_info = rpc.get_epoch_info().await?;
+
+            let up_to_date = route::check_stake_pool_up_to_date(&spl_stake_pool_state, epoch_info.epoch);
+
+            let routes = [
+                route::RouteQuote::new(
+                    route::RouteKind::Instant,
+                    quote_lst_unstake(&spl_stake_pool_state, &unstake_pool_info, amount),
+                    &epoch_info,
+                ),
+                route::RouteQuote::new(
+                    route::RouteKind::WithdrawSol,
+                    up_to_date.as_ref().map_err(|err| anyhow::anyhow!("{}", err)).and_then(|_| {
+                        route::quote_withdraw_sol_route(
+                            &spl_stake_pool_state,
+                            reserve_lamports,
+                            reserve_rent_exempt_reserve,
+                            amount,
+                        )
+                    }),
+                    &epoch_info,
+                ),
+                route::RouteQuote::new(
+                    route::RouteKind::Delayed,
+                    up_to_date.as_ref().map_err(|err| anyhow::anyhow!("{}", err)).and_then(|_| {
+                        route::quote_delayed_route(&spl_stake_pool_state, &spl_stake_pool_validator_list, amount)
+                    }),
+                    &epoch_info,
+                ),
+            ];
+
+            println!("Routes for {} {:?} tokens (epoch {})", amount, mint, epoch_info.epoch);
+            println!("{:<14} {:>20} {:>14} {:>18} Note", "Route", "Net lamports", "Liquid epoch", "Time to liquidity");
+
+            for quote in routes.iter() {
+                println!(
+                    "{:<14} {:>20} {:>14} {:>18} {}",
+                    quote.kind.name(),
+                    quote.lamports_out.map(|lamports| lamports.to_string()).unwrap_or("-".to_string()),
+                    quote.liquid_epoch,
+                    match quote.seconds_to_liquidity {
+                        0 => "now".to_string(),
+                        seconds => format!("~{}h {}m", seconds / 3600, seconds % 3600 / 60),
                     },
+                    quote.unavailable_reason.as_deref().unwrap_or(""),
+                );
+            }
+
+            if let Some(best) = routes
+                .iter()
+                .filter(|quote| quote.lamports_out.is_some())
+                .max_by_key(|quote| quote.lamports_out)
+            {
+                println!("Most lamports: {}", best.kind.name());
+            }
+
+            let Some(kind) = arg_matches.get_one::<String>("execute").and_then(|name| route::RouteKind::from_name(name))
+            else {
+                return Ok(());
+            };
+
+            let quote = routes.iter().find(|quote| quote.kind == kind).unwrap();
+            let Some(lamports_out) = quote.lamports_out else {
+                return Err(anyhow::anyhow!(
+                    "Route {} is not available: {}",
+                    kind.name(),
+                    quote.unavailable_reason.as_deref().unwrap_or("")
+                ));
+            };
+
+            let wallet = wallet_keypair.pubkey();
+
+            // The stake pool withdrawals are signed by the wallet, so every route takes the LST from the same account
+            if authority.pubkey() != wallet {
+                return Err(anyhow::anyhow!(
+                    "Routes are executed with the wallet as transfer authority, --authority and --delegate are not supported"
+                ));
+            }
+
+            let user_lst_account = unstake_options.source_lst_account.unwrap_or(
+                associated_token::get_associated_token_address_with_program_id(
+                    &wallet,
+                    &mint,
+                    &spl_stake_pool_state.token_program_id,
+                ),
+            );
+            let stake_pool_withdraw_authority = Pubkey::find_program_address(
+                &[&spl_stake_pool_address.to_bytes(), b"withdraw"],
+                &spl_stake_pool_program_id,
+            )
+            .0;
+
+            let mut prefix = vec![];
+            if let Some(compute_unit_price) = unstake_options.compute_unit_price {
+                prefix.push(solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price));
+            }
+
+            let mut new_stake_accounts = vec![];
+
+            let instructions = match kind {
+                route::RouteKind::Instant => {
+                    unstake_lst(
+                        &program,
+                        &unstake_pool_id,
+                        fee_payer,
+                        &authority,
+                        &spl_stake_pool_program_id,
+                        &mint,
+                        &unstake_pool_info,
+                        amount,
+                        simulate,
+                        new_stake_account_as_pda,
+                        &unstake_options,
+                    )
+                    .await?;
+
+                    return Ok(());
+                }
+                route::RouteKind::WithdrawSol => {
+                    let recipient = unstake_options.recipient.unwrap_or(wallet);
+
+                    vec![match unstake_options.slippage_bps {
+                        Some(slippage_bps) => spl_stake_pool::instruction::withdraw_sol_with_slippage(
+                            &spl_stake_pool_program_id,
+                            &spl_stake_pool_address,
+                            &stake_pool_withdraw_authority,
+                            &wallet,
+                            &user_lst_account,
+                            &spl_stake_pool_state.reserve_stake,
+                            &recipient,
+                            &spl_stake_pool_state.manager_fee_account,
+                            &mint,
+                            &spl_stake_pool_state.token_program_id,
+                            amount,
+                            apply_slippage(lamports_out, slippage_bps),
+                        ),
+                        None => spl_stake_pool::instruction::withdraw_sol(
+                            &spl_stake_pool_program_id,
+                            &spl_stake_pool_address,
+                            &stake_pool_withdraw_authority,
+                            &wallet,
+                            &user_lst_account,
+                            &spl_stake_pool_state.reserve_stake,
+                            &recipient,
+                            &spl_stake_pool_state.manager_fee_account,
+                            &mint,
+                            &spl_stake_pool_state.token_program_id,
+                            amount,
+                        ),
+                    }]
+                }
+                route::RouteKind::Delayed => {
+                    // Split the tokens over the validator stake accounts the same way the liquid unstake does
+                    let (lst_amounts, withdraw_stake_accounts, _, _) = get_unstake_accounts(
+                        &program.id(),
+                        &spl_stake_pool_program_id,
+                        &spl_stake_pool_address,
+                        &spl_stake_pool_state,
+                        &spl_stake_pool_validator_list,
+                        amount,
+                    )?;
+                    let withdrawals = withdraw_stake_accounts.into_iter().zip(lst_amounts).collect_vec();
+                    new_stake_accounts = withdrawals.iter().map(|_| Keypair::new()).collect_vec();
+
+                    for new_stake_account in new_stake_accounts.iter() {
+                        println!("New stake account: {}", new_stake_account.pubkey());
+                    }
+
+                    route::get_delayed_route_instructions(
+                        &spl_stake_pool_program_id,
+                        &spl_stake_pool_address,
+                        &spl_stake_pool_state,
+                        &fee_payer.pubkey(),
+                        &wallet,
+                        &user_lst_account,
+                        &withdrawals,
+                        &new_stake_accounts,
+                    )
+                }
+            };
+
+            let transactions = stake::pack_instructions_into_transactions(instructions, &prefix, &fee_payer.pubkey())?;
+
+            let mut signers = vec![fee_payer, &wallet_keypair];
+            signers.extend(new_stake_accounts.iter());
+
+            for (index, instructions) in transactions.iter().enumerate() {
+                println!("Transaction {}/{}", index + 1, transactions.len());
+
+                let mut tx = Transaction::new_with_payer(instructions, Some(&fee_payer.pubkey()));
+                let recent_blockhash = rpc.get_latest_blockhash().await?;
+
+                // Every transaction only needs the keypairs of the stake accounts it creates
+                let tx_signers = signers
+                    .iter()
+                    .copied()
+                    .filter(|signer| tx.message.signer_keys().contains(&&signer.pubkey()))
+                    .unique_by(|signer| signer.pubkey())
+                    .collect_vec();
+                tx.try_sign(&tx_signers, recent_blockhash)?;
+
+                // The withdrawals are sent in order, stop at the first one that fails
+                send_or_simulate_transaction_checked(&rpc, &tx, simulate, Some(vec![wallet]))
+                    .await
+                    .map_err(|err| {
+                        anyhow::anyhow!(
+                            "Transaction {}/{} failed, the later ones were not sent: {}",
+                            index + 1,
+                            transactions.len(),
+                            err
+                        )
+                    })?;
+            }
+
+            if kind == route::RouteKind::Delayed {
+                println!(
+                    "The stake is deactivating, claim it from epoch {} with claim-stake",
+                    quote.liquid_epoch
+                );
+            }
+        }
+        Some(("stake-accounts", arg_matches)) => {
+            let rpc = program.rpc();
+            let owner = match arg_matches.get_one::<String>("owner") {
+                Some(owner) => Pubkey::from_str(owner)?,
+                None => wallet_keypair.pubkey(),
+            };
+
+            let stake_accounts = stake::get_wallet_stake_accounts(&rpc, &owner).await?;
+            let clock = stake::get_clock(&rpc).await?;
+
+            println!(
+                "{:<44} {:<12} {:<44} {:>20} {:>12} {:<24} {:>20} Issues",
+                "Stake account", "Status", "Validator", "Lamports", "Rent reserve", "Lockup", "Unstake quote"
+            );
+
+            for stake_account in stake_accounts.iter() {
+                let lockup = &stake_account.meta.lockup;
+                let lockup = if lockup.is_in_force(&clock, None) {
+                    format!("epoch {} / ts {}", lockup.epoch, lockup.unix_timestamp)
+                } else {
+                    "none".to_string()
+                };
+
+                let issues = stake::get_unstake_stake_account_issues(stake_account, &clock);
+                let quote = if issues.is_empty() {
+                    stake::quote_stake_account_unstake(&unstake_pool_info, stake_account.lamports)
+                        .map(|(quote, _)| quote.to_string())
+                        .unwrap_or("-".to_string())
+                } else {
+                    "-".to_string()
+                };
+
+                println!(
+                    "{:<44} {:<12} {:<44} {:>20} {:>12} {:<24} {:>20} {}",
+                    stake_account.address.to_string(),
+                    format!("{:?}", stake_account.status),
+                    stake_account.voter().map(|voter| voter.to_string()).unwrap_or("-".to_string()),
+                    stake_account.lamports,
+                    stake_account.meta.rent_exempt_reserve,
+                    lockup,
+                    quote,
+                    issues.join(", "),
+                );
+            }
+
+            println!("{} stake accounts", stake_accounts.len());
+        }
+        Some(("portfolio", arg_matches)) => {
+            let owner = match arg_matches.get_one::<String>("owner") {
+                Some(owner) => Pubkey::from_str(owner)?,
+                None => wallet_keypair.pubkey(),
+            };
+
+            let holdings = portfolio::get_lst_holdings(&program.rpc(), &owner, &unstake_pool_info).await?;
+
+            println!(
+                "{:<44} {:<44} {:>20} {:>20} {:>20} {:>10}",
+                "Token account", "Mint", "Balance", "SOL value", "Unstake value", "Discount"
+            );
+
+            for holding in holdings.iter() {
+                println!(
+                    "{:<44} {:<44} {:>20} {:>20} {:>20} {:>10}",
+                    holding.token_account.to_string(),
+                    holding.mint.to_string(),
+                    holding.amount,
+                    holding.sol_value,
+                    holding.unstake_value.map(|v| v.to_string()).unwrap_or("-".to_string()),
+                    holding.discount_pct().map(|d| format!("{:.4}%", d)).unwrap_or("-".to_string()),
+                );
+            }
+
+            println!(
+                "Total: {} lamports at stake pool rates, {} lamports when instantly unstaked",
+                holdings.iter().map(|h| h.sol_value).sum::<u64>(),
+                holdings.iter().filter_map(|h| h.unstake_value).sum::<i64>(),
+            );
+        }
+        Some(("batch", arg_matches)) => {
+            let file = std::path::Path::new(arg_matches.get_one::<String>("file").unwrap());
+            let output = std::path::Path::new(arg_matches.get_one::<String>("output").unwrap());
+            let concurrency = *arg_matches.get_one::<usize>("concurrency").unwrap();
+
+            let rows = batch::read_batch_file(file)?;
+
+            batch::run_batch(
+                &program,
+                &unstake_pool_id,
+                fee_payer,
+                &wallet_keypair,
+                &rows,
+                output,
+                concurrency,
+                simulate,
+                new_stake_account_as_pda,
+                unstake_options.compute_unit_price,
+                unstake_options.slippage_bps,
+            )
+            .await?;
+        }
+        Some(("order", arg_matches)) => {
+            let order_book_path = arg_matches
+                .get_one::<String>("orders")
+                .map(std::path::PathBuf::from)
+                .or(order::get_default_order_book_path())
+                .ok_or(anyhow::anyhow!("No config directory found, use --orders"))?;
+
+            match arg_matches.subcommand() {
+                Some(("add", arg_matches)) => {
+                    let mint = Pubkey::from_str(arg_matches.get_one::<String>("mint").unwrap())?;
+                    let amount = *arg_matches.get_one::<u64>("amount").unwrap();
+                    let rpc = program.rpc();
+
+                    let spl_stake_pool_program_id = get_stake_pool_program_for_lst_mint(&rpc, &mint)
+                        .await?
+                        .ok_or(anyhow::anyhow!("Could not find a supported stake pool for the given mint"))?;
+                    let (_, spl_stake_pool_state) =
+                        get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
+                    let spl_stake_pool_validator_list = rpc
+                        .get_account(&spl_stake_pool_state.validator_list)
+                        .await
+                        .map(|account| {
+                            let mut data = account.data.as_slice();
+                            spl_stake_pool::state::ValidatorList::deserialize(&mut data)
+                        })??;
+
+                    // An order is filled by a single unstake, which takes at most 5 validator stake accounts
+                    let max_pool_tokens = max_unstake::get_max_pool_tokens_for_stake_accounts(
+                        &spl_stake_pool_state,
+                        &spl_stake_pool_validator_list,
+                        max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE,
+                    );
+
+                    if amount > max_pool_tokens {
+                        return Err(anyhow::anyhow!(
+                            "A single unstake can take at most {} tokens, add several smaller orders instead",
+                            max_pool_tokens
+                        ));
+                    }
+
+                    let now = chrono::Utc::now().timestamp();
+                    let order = order::add_order(
+                        &order_book_path,
+                        &unstake_pool_id,
+                        &mint,
+                        amount,
+                        *arg_matches.get_one::<u32>("max-fee-bps").unwrap(),
+                        unstake_options.recipient,
+                        arg_matches.get_one::<i64>("expires-in").map(|expires_in| now + expires_in),
+                        now,
+                    )?;
+
+                    println!("Added order {} to {}", order.id, order_book_path.display());
+                }
+                Some(("list", _)) => {
+                    let orders = order::read_order_book(&order_book_path)?;
+
+                    println!(
+                        "{:>6} {:<10} {:<44} {:>20} {:>8} {:<20} {:>20} Signature / error",
+                        "Id", "Status", "Mint", "Amount", "Max fee", "Expires", "Received"
+                    );
+
+                    for order in orders.iter() {
+                        println!(
+                            "{:>6} {:<10} {:<44} {:>20} {:>8} {:<20} {:>20} {}",
+                            order.id,
+                            format!("{:?}", order.status),
+                            order.mint,
+                            order.amount,
+                            format!("{} bps", order.max_fee_bps),
+                            order
+                                .expires_at
+                                .and_then(|expires_at| chrono::DateTime::from_timestamp(expires_at, 0))
+                                .map(|expires_at| expires_at.format("%Y-%m-%d %H:%M:%S").to_string())
+                                .unwrap_or("-".to_string()),
+                            order.lamports_received.map(|lamports| lamports.to_string()).unwrap_or("-".to_string()),
+                            order.signature.as_deref().or(order.error.as_deref()).unwrap_or(""),
+                        );
+                    }
+                }
+                Some(("cancel", arg_matches)) => {
+                    let order = order::cancel_order(&order_book_path, *arg_matches.get_one::<u64>("id").unwrap())?;
+
+                    println!("Cancelled order {}", order.id);
+                }
+                Some(("run", arg_matches)) => {
+                    let interval = *arg_matches.get_one::<u64>("interval").unwrap();
+                    let mut stake_pool_programs = std::collections::HashMap::new();
+
+                    loop {
+                        let result = order::run_orders(
+                            &program,
+                            fee_payer,
+                            &wallet_keypair,
+                            &order_book_path,
+                            &mut stake_pool_programs,
+                            simulate,
+                            new_stake_account_as_pda,
+                            unstake_options.compute_unit_price,
+                            unstake_options.slippage_bps,
+                            chrono::Utc::now().timestamp(),
+                        )
+                        .await;
+
+                        if arg_matches.get_flag("once") {
+                            return result;
+                        }
+
+                        // A failed poll, e.g. an RPC error, is retried on the next one
+                        if let Err(err) = result {
+                            println!("Running the orders failed: {}", err);
+                        }
+
+                        tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        Some(("twap-unstake", arg_matches)) => {
+            let rpc = program.rpc();
+            let mint = Pubkey::from_str(arg_matches.get_one::<String>("mint").unwrap())?;
+            let amount = *arg_matches.get_one::<u64>("amount").unwrap();
+            let schedule = match (arg_matches.get_one::<u64>("duration"), arg_matches.get_one::<u64>("epochs")) {
+                (Some(duration), _) => twap::TwapSchedule::Duration(*duration),
+                (None, Some(epochs)) => twap::TwapSchedule::Epochs(*epochs),
+                (None, None) => unreachable!(),
+            };
+            let max_marginal_fee_pct = *arg_matches.get_one::<f64>("max-marginal-fee-pct").unwrap();
+            let interval = *arg_matches.get_one::<u64>("interval").unwrap();
+
+            let state_path = match arg_matches.get_one::<String>("state") {
+                Some(state_path) => std::path::PathBuf::from(state_path),
+                None => config::get_config_dir()
+                    .ok_or(anyhow::anyhow!("No config directory found, use --state"))?
+                    .join("twap")
+                    .join(format!("{}-{}.json", unstake_pool_id, mint)),
+            };
+
+            let spl_stake_pool_program_id = get_stake_pool_program_for_lst_mint(&rpc, &mint)
+                .await?
+                .ok_or(anyhow::anyhow!("Could not find a supported stake pool for the given mint"))?;
+
+            let mut state = match twap::read_twap_state(&state_path)? {
+                Some(state) => {
+                    if state.pool != unstake_pool_id.to_string()
+                        || state.mint != mint.to_string()
+                        || state.amount != amount
+                        || state.schedule != schedule
+                        || state.max_marginal_fee_pct != max_marginal_fee_pct
+                    {
+                        return Err(anyhow::anyhow!(
+                            "{} holds a TWAP unstake of {} {} tokens on pool {} over {:?} with a {}% marginal fee cap, refusing to resume with other parameters",
+                            state_path.display(),
+                            state.amount,
+                            state.mint,
+                            state.pool,
+                            state.schedule,
+                            state.max_marginal_fee_pct
+                        ));
+                    }
+
+                    println!("Resuming the TWAP unstake from {}", state_path.display());
+
+                    state
+                }
+                None => {
+                    let state = twap::TwapState {
+                        pool: unstake_pool_id.to_string(),
+                        mint: mint.to_string(),
+                        amount,
+                        schedule,
+                        max_marginal_fee_pct,
+                        start_timestamp: chrono::Utc::now().timestamp(),
+                        start_epoch: rpc.get_epoch_info().await?.epoch,
+                        slices: vec![],
+                    };
+
+                    if !simulate {
+                        twap::write_twap_state(&state_path, &state)?;
+                    }
+
+                    state
+                }
+            };
+
+            loop {
+                let done = twap::run_twap_step(
+                    &program,
+                    fee_payer,
+                    &wallet_keypair,
+                    &spl_stake_pool_program_id,
+                    &state_path,
+                    &mut state,
+                    simulate,
+                    new_stake_account_as_pda,
+                    &unstake_options,
                 )
-                .instructions()?;
+                .await?;
 
-            // Build transaction
-            let recent_blockhash = program.rpc().get_latest_blockhash().await?;
+                println!(
+                    "Unstaked {} of {} tokens for {} lamports",
+                    state.get_unstaked_pool_tokens(),
+                    state.amount,
+                    state.get_lamports_received()
+                );
 
-            let tx = Transaction::new_signed_with_payer(
-                &instructions,
-                Some(&wallet_keypair.pubkey()),
-                &[&wallet_keypair],
-                recent_blockhash,
-            );
+                if done {
+                    break;
+                }
 
-            // Send or simulate the transaction
-            send_or_simulate_transaction(&program.rpc(), &tx, simulate, None).await?;
+                tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+            }
         }
-        Some(("list-lst-mints", arg_matches)) => {
-            let limit = *arg_matches.get_one::<u64>("limit").unwrap_or(&u64::MAX);
-
+        Some(("watch-quote", arg_matches)) => {
             let rpc = program.rpc();
+            let mint = Pubkey::from_str(arg_matches.get_one::<String>("mint").unwrap())?;
+            let amounts = arg_matches.get_many::<u64>("amounts").unwrap().copied().collect_vec();
+
+            let spl_stake_pool_program_id = get_stake_pool_program_for_lst_mint(&rpc, &mint)
+                .await?
+                .ok_or(anyhow::anyhow!("Could not find a supported stake pool for the given mint"))?;
+            let (spl_stake_pool_address, spl_stake_pool_state) =
+                get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
+
+            watch_quote::watch_quotes(
+                &rpc,
+                &config.websocket_url,
+                config.commitment,
+                &unstake_pool_id,
+                &spl_stake_pool_address,
+                &spl_stake_pool_state.validator_list,
+                &mint,
+                &amounts,
+            )
+            .await?;
+        }
+        Some(("unstake-all", arg_matches)) => {
+            let mints = arg_matches
+                .get_many::<String>("mints")
+                .unwrap_or_default()
+                .map(|mint| Pubkey::from_str(mint))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+
+            // The unstakes are signed by the authority, so its holdings are the ones unstaked
+            let owner = authority.pubkey();
+
+            let holdings = portfolio::get_lst_holdings(&program.rpc(), &owner, &unstake_pool_info)
+                .await?
+                .into_iter()
+                .filter(|holding| mints.is_empty() || mints.contains(&holding.mint))
+                .filter(|holding| {
+                    // The unstake instructions always take the LST from the authority's ATA
+                    let is_ata = holding.token_account
+                        == associated_token::get_associated_token_address_with_program_id(
+                            &owner,
+                            &holding.mint,
+                            &holding.stake_pool_state.token_program_id,
+                        );
+
+                    if !is_ata {
+                        println!("Skipping {} as it is not the associated token account for {}", holding.token_account, holding.mint);
+                    }
+
+                    is_ata
+                })
+                .collect_vec();
+
+            // A single unstake takes at most 5 validator stake accounts, larger holdings are unstaked in several
+            let mut max_pool_tokens_per_unstake = vec![];
+
+            for holding in holdings.iter() {
+                let spl_stake_pool_validator_list = program
+                    .rpc()
+                    .get_account(&holding.stake_pool_state.validator_list)
+                    .await
+                    .map(|account| {
+                        let mut data = account.data.as_slice();
+                        spl_stake_pool::state::ValidatorList::deserialize(&mut data)
+                    })??;
+                let max_pool_tokens = max_unstake::get_max_pool_tokens_for_stake_accounts(
+                    &holding.stake_pool_state,
+                    &spl_stake_pool_validator_list,
+                    max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE,
+                );
 
-            let mut mints = vec![];
+                if max_pool_tokens == 0 {
+                    println!("Skipping {} as its stake pool has no validator stake to withdraw from", holding.mint);
+                }
 
-            for program_id in SUPPORTED_STAKE_POOL_PROGRAMS {
-                let mints_for_program = get_stake_pool_mints(&rpc, &program_id).await?;
-                mints.extend(mints_for_program);
+                max_pool_tokens_per_unstake.push(max_pool_tokens);
             }
 
-            mints
-                .into_iter()
-                .take(limit as usize)
-                .for_each(|mint| println!("{:?}", mint));
-        }
-        Some(("pool-info", _arg_matches)) => {
+            let plan = portfolio::plan_unstake_all(&holdings, &max_pool_tokens_per_unstake, &unstake_pool_info)?;
+
+            for (i, step) in plan.iter().enumerate() {
+                println!(
+                    "{}. Unstake {} {} for ~{} lamports (vault at {} lamports)",
+                    i + 1,
+                    step.amount,
+                    holdings[step.holding].mint,
+                    step.lamports_out,
+                    step.sol_vault_lamports,
+                );
+            }
+
+            println!("Expected total: {} lamports", plan.iter().map(|step| step.lamports_out).sum::<i64>());
 
-            println!("{:#?}", unstake_pool_info);
+            for step in plan.iter() {
+                let holding = &holdings[step.holding];
+
+                // The stake account seed depends on the pool state, so it has to be reloaded after every unstake
+                let unstake_pool_info = program
+                    .account::<liquid_unstaker::liquid_unstaker::accounts::Pool>(unstake_pool_id)
+                    .await?;
+
+                unstake_lst(
+                    &program,
+                    &unstake_pool_id,
+                    fee_payer,
+                    &authority,
+                    &holding.stake_pool_program_id,
+                    &holding.mint,
+                    &unstake_pool_info,
+                    step.amount,
+                    simulate,
+                    new_stake_account_as_pda,
+                    &UnstakeLstOptions {
+                        recipient,
+                        close_empty_lst_account: unstake_options.close_empty_lst_account,
+                        compute_unit_price: unstake_options.compute_unit_price,
+                        slippage_bps: unstake_options.slippage_bps,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            }
         }
         _ => {
             println!("No valid subcommand was provided");
@@ -458,18 +2417,68 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn unstake_lst(
     program: &anchor_client::Program<Rc<Keypair>>,
     unstake_pool_id: &Pubkey,
-    wallet_keypair: &Keypair,
+    payer: &Keypair,
+    authority: &PubkeyOrKeypair,
     spl_stake_pool_program_id: &Pubkey,
     mint: &Pubkey,
     unstake_pool_info: &liquid_unstaker::liquid_unstaker::accounts::Pool,
     amount: u64,
     simulate: bool,
     new_stake_account_as_pda: bool,
+    options: &UnstakeLstOptions<'_>,
 ) -> Result<()> {
+    let (tx, simulation_accounts_of_interest) = build_unstake_lst_transaction(
+        program,
+        unstake_pool_id,
+        payer,
+        authority,
+        spl_stake_pool_program_id,
+        mint,
+        unstake_pool_info,
+        amount,
+        new_stake_account_as_pda,
+        options,
+    )
+    .await?;
+
+    if !simulate && !tx.is_signed() {
+        println!(
+            "Transaction signed by the fee payer, it has to be co-signed by {} before sending (base64): {}",
+            authority.pubkey(),
+            serialize_transaction_base64(&tx)?
+        );
+
+        return Ok(());
+    }
+
+    // Send or simulate the transaction
+    send_or_simulate_transaction(&program.rpc(), &tx, simulate, Some(simulation_accounts_of_interest)).await?;
+
+    Ok(())
+}
+
+/// Build and sign the liquid unstake transaction for the given amount of LST tokens, see [`UnstakeLstOptions`] for
+/// where the tokens are taken from and where the SOL is sent to. Also returns the accounts whose balances are of
+/// interest when simulating
+#[allow(clippy::too_many_arguments)]
+async fn build_unstake_lst_transaction(
+    program: &anchor_client::Program<Rc<Keypair>>,
+    unstake_pool_id: &Pubkey,
+    payer: &Keypair,
+    authority: &PubkeyOrKeypair,
+    spl_stake_pool_program_id: &Pubkey,
+    mint: &Pubkey,
+    unstake_pool_info: &liquid_unstaker::liquid_unstaker::accounts::Pool,
+    amount: u64,
+    new_stake_account_as_pda: bool,
+    options: &UnstakeLstOptions<'_>,
+) -> Result<(Transaction, Vec<Pubkey>)> {
     let rpc = program.rpc();
+    let recipient = &options.recipient.unwrap_or(authority.pubkey());
 
     let (spl_stake_pool_address, spl_stake_pool_state) =
         get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
@@ -491,16 +2500,18 @@ async fn unstake_lst(
         if new_stake_account_as_pda {
         
             get_unstake_accounts_with_new_stake_account_as_pda(
+                &program.id(),
                 &spl_stake_pool_program_id,
                 &spl_stake_pool_address,
                 &spl_stake_pool_state,
                 &spl_stake_pool_validator_list,
                 stake_account_seed,
-                &wallet_keypair.pubkey(),
+                &payer.pubkey(),
                 amount,
             )?
         } else {
             get_unstake_accounts(
+                &program.id(),
                 &spl_stake_pool_program_id,
                 &spl_stake_pool_address,
                 &spl_stake_pool_state,
@@ -509,16 +2520,42 @@ async fn unstake_lst(
             )?
         };
 
+    // The liquid unstake instructions take at most 5 validator stake accounts
+    if lst_amounts.len() > max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE {
+        return Err(anyhow::anyhow!(
+            "Unstaking {} tokens needs {} validator stake accounts, a single unstake takes at most {}",
+            amount,
+            lst_amounts.len(),
+            max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE
+        ));
+    }
+
+    let minimum_lamports_out = match (options.minimum_lamports_out, options.slippage_bps) {
+        (Some(minimum_lamports_out), _) => Some(minimum_lamports_out),
+        (None, Some(slippage_bps)) => {
+            let quote = quote_lst_unstake(&spl_stake_pool_state, unstake_pool_info, amount)?;
+
+            Some(apply_slippage(quote, slippage_bps))
+        }
+        (None, None) => None,
+    };
+
     let lst_amounts = lst_amounts
         .into_iter()
         .pad_using(5, |_| 0)
         .collect_array::<5>()
         .unwrap();
 
-    let wallet_lst_token_ata = associated_token::get_associated_token_address(
-        &wallet_keypair.pubkey(),
-        &spl_stake_pool_state.pool_mint,
-    );
+    let (user_lst_account, user_lst_account_state, approve_instructions) =
+        get_user_lst_account(
+            &rpc,
+            authority,
+            &spl_stake_pool_state.pool_mint,
+            &spl_stake_pool_state.token_program_id,
+            amount,
+            options,
+        )
+        .await?;
 
     let stake_pool_withdraw_authority = Pubkey::find_program_address(
         &[&spl_stake_pool_address.to_bytes(), b"withdraw"],
@@ -532,11 +2569,11 @@ async fn unstake_lst(
             liquid_unstaker::liquid_unstaker::client::accounts::LiquidUnstakeLst {
                 pool: *unstake_pool_id,
                 sol_vault: unstake_pool_info.sol_vault,
-                token_program: spl_token::id(),
-                payer: wallet_keypair.pubkey(),
-                user_transfer_authority: wallet_keypair.pubkey(),
-                user_lst_account: wallet_lst_token_ata,
-                user_sol_account: wallet_keypair.pubkey(),
+                token_program: spl_stake_pool_state.token_program_id,
+                payer: payer.pubkey(),
+                user_transfer_authority: authority.pubkey(),
+                user_lst_account,
+                user_sol_account: *recipient,
                 manager_fee_account: unstake_pool_info.manager_fee_account,
                 stake_pool: spl_stake_pool_address,
                 stake_pool_validator_list: spl_stake_pool_state.validator_list,
@@ -570,7 +2607,7 @@ async fn unstake_lst(
             .args(
                 liquid_unstaker::liquid_unstaker::client::args::LiquidUnstakeLstWithSeed {
                     lst_amounts,
-                    minimum_lamports_out: None,
+                    minimum_lamports_out,
                     stake_account_seed: stake_account_seed,
                 },
             )
@@ -581,7 +2618,7 @@ async fn unstake_lst(
             .args(
                 liquid_unstaker::liquid_unstaker::client::args::LiquidUnstakeLst {
                     lst_amounts,
-                    minimum_lamports_out: None,
+                    minimum_lamports_out,
                 },
             )
             .instructions()?
@@ -593,7 +2630,7 @@ async fn unstake_lst(
             .iter()
             .map(|stake_account_keypair| {
                 create_account(
-                    &wallet_keypair.pubkey(),
+                    &payer.pubkey(),
                     &stake_account_keypair.pubkey(),
                     solana_sdk::rent::Rent::default()
                         .minimum_balance(solana_sdk::stake::state::StakeStateV2::size_of()),
@@ -607,12 +2644,41 @@ async fn unstake_lst(
     }
 
 
+    instructions.splice(0..0, approve_instructions);
+
+    instructions.extend(get_close_empty_lst_account_instructions(
+        payer,
+        authority,
+        &spl_stake_pool_state.token_program_id,
+        &user_lst_account,
+        &user_lst_account_state,
+        amount,
+        options,
+    )?);
+
+    if let Some(compute_unit_price) = options.compute_unit_price {
+        instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price));
+    }
+
     instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_limit(1_000_000));
 
     // Build transaction
     let recent_blockhash = rpc.get_latest_blockhash().await?;
 
-    let mut signers = vec![wallet_keypair];
+    // The authority only signs here if we hold its keypair, otherwise it has to co-sign the transaction later
+    let mut signers = vec![payer];
+
+    if let PubkeyOrKeypair::Keypair(authority) = authority {
+        if authority.pubkey() != payer.pubkey() {
+            signers.push(authority);
+        }
+    }
+
+    if let Some(approve_owner) = options.approve_owner {
+        if signers.iter().all(|signer| signer.pubkey() != approve_owner.pubkey()) {
+            signers.push(approve_owner);
+        }
+    }
 
     if !new_stake_account_as_pda {
         
@@ -625,37 +2691,85 @@ async fn unstake_lst(
         }
     }
 
-    let tx = Transaction::new_signed_with_payer(
-        &instructions,
-        Some(&wallet_keypair.pubkey()),
-        &signers,
-        recent_blockhash,
-    );
+    let mut tx = Transaction::new_with_payer(&instructions, Some(&payer.pubkey()));
+    tx.try_partial_sign(&signers, recent_blockhash)?;
 
-    // Send or simulate the transaction
-    send_or_simulate_transaction(&program.rpc(), &tx, simulate, 
-        Some(vec![
-            wallet_keypair.pubkey(), 
-            unstake_pool_info.sol_vault,
-            unstake_pool_info.manager_fee_account,
-            new_stake_accounts[0].pubkey(),
-        ])).await?;
+    let mut simulation_accounts_of_interest = vec![
+        payer.pubkey(),
+        unstake_pool_info.sol_vault,
+        unstake_pool_info.manager_fee_account,
+        new_stake_accounts[0].pubkey(),
+    ];
 
-    Ok(())
+    if *recipient != payer.pubkey() {
+        simulation_accounts_of_interest.insert(1, *recipient);
+    }
+
+    Ok((tx, simulation_accounts_of_interest))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn unstake_lst_wrapped(
     program: &anchor_client::Program<Rc<Keypair>>,
     unstake_pool_id: &Pubkey,
-    wallet_keypair: &Keypair,
+    payer: &Keypair,
+    authority: &PubkeyOrKeypair,
     spl_stake_pool_program_id: &Pubkey,
     mint: &Pubkey,
     unstake_pool_info: &liquid_unstaker::liquid_unstaker::accounts::Pool,
     amount: u64,
     simulate: bool,
     new_stake_account_as_pda: bool,
+    options: &UnstakeLstOptions<'_>,
 ) -> Result<()> {
+    let (tx, simulation_accounts_of_interest) = build_unstake_lst_wrapped_transaction(
+        program,
+        unstake_pool_id,
+        payer,
+        authority,
+        spl_stake_pool_program_id,
+        mint,
+        unstake_pool_info,
+        amount,
+        new_stake_account_as_pda,
+        options,
+    )
+    .await?;
+
+    if !simulate && !tx.is_signed() {
+        println!(
+            "Transaction signed by the fee payer, it has to be co-signed by {} before sending (base64): {}",
+            authority.pubkey(),
+            serialize_transaction_base64(&tx)?
+        );
+
+        return Ok(());
+    }
+
+    // Send or simulate the transaction
+    send_or_simulate_transaction(&program.rpc(), &tx, simulate, Some(simulation_accounts_of_interest)).await?;
+
+    Ok(())
+}
+
+/// Build and sign the liquid unstake transaction for the given amount of LST tokens, see [`UnstakeLstOptions`] for
+/// where the tokens are taken from. The wSOL is sent to the wSOL ATA of the recipient, which is created if it does
+/// not exist yet. Also returns the accounts whose balances are of interest when simulating
+#[allow(clippy::too_many_arguments)]
+async fn build_unstake_lst_wrapped_transaction(
+    program: &anchor_client::Program<Rc<Keypair>>,
+    unstake_pool_id: &Pubkey,
+    payer: &Keypair,
+    authority: &PubkeyOrKeypair,
+    spl_stake_pool_program_id: &Pubkey,
+    mint: &Pubkey,
+    unstake_pool_info: &liquid_unstaker::liquid_unstaker::accounts::Pool,
+    amount: u64,
+    new_stake_account_as_pda: bool,
+    options: &UnstakeLstOptions<'_>,
+) -> Result<(Transaction, Vec<Pubkey>)> {
     let rpc = program.rpc();
+    let recipient = &options.recipient.unwrap_or(authority.pubkey());
 
     let (spl_stake_pool_address, spl_stake_pool_state) =
         get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
@@ -670,6 +2784,8 @@ async fn unstake_lst_wrapped(
             spl_stake_pool::state::ValidatorList::deserialize(&mut data)
         })??;
 
+    check_wrapped_unstake_token_program(&spl_stake_pool_state)?;
+
     // Get all the accounts we need for liquid unstaking, and calculate amounts to pass to the liquid unstake instruction
     let stake_account_seed = unstake_pool_info.total_deactivating_stake;
 
@@ -677,16 +2793,18 @@ async fn unstake_lst_wrapped(
         if new_stake_account_as_pda {
         
             get_unstake_accounts_with_new_stake_account_as_pda(
+                &program.id(),
                 &spl_stake_pool_program_id,
                 &spl_stake_pool_address,
                 &spl_stake_pool_state,
                 &spl_stake_pool_validator_list,
                 stake_account_seed,
-                &wallet_keypair.pubkey(),
+                &payer.pubkey(),
                 amount,
             )?
         } else {
             get_unstake_accounts(
+                &program.id(),
                 &spl_stake_pool_program_id,
                 &spl_stake_pool_address,
                 &spl_stake_pool_state,
@@ -695,19 +2813,45 @@ async fn unstake_lst_wrapped(
             )?
         };
 
+    // The liquid unstake instructions take at most 5 validator stake accounts
+    if lst_amounts.len() > max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE {
+        return Err(anyhow::anyhow!(
+            "Unstaking {} tokens needs {} validator stake accounts, a single unstake takes at most {}",
+            amount,
+            lst_amounts.len(),
+            max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE
+        ));
+    }
+
+    let minimum_lamports_out = match (options.minimum_lamports_out, options.slippage_bps) {
+        (Some(minimum_lamports_out), _) => Some(minimum_lamports_out),
+        (None, Some(slippage_bps)) => {
+            let quote = quote_lst_unstake_wrapped(&spl_stake_pool_state, unstake_pool_info, amount, new_stake_account_as_pda)?.0;
+
+            Some(apply_slippage(quote, slippage_bps))
+        }
+        (None, None) => None,
+    };
+
     let lst_amounts = lst_amounts
         .into_iter()
         .pad_using(5, |_| 0)
         .collect_array::<5>()
         .unwrap();
 
-    let wallet_lst_token_ata = associated_token::get_associated_token_address(
-        &wallet_keypair.pubkey(),
-        &spl_stake_pool_state.pool_mint,
-    );
+    let (user_lst_account, user_lst_account_state, approve_instructions) =
+        get_user_lst_account(
+            &rpc,
+            authority,
+            &spl_stake_pool_state.pool_mint,
+            &spl_stake_pool_state.token_program_id,
+            amount,
+            options,
+        )
+        .await?;
 
-    let wallet_wsol_token_ata = associated_token::get_associated_token_address(
-        &wallet_keypair.pubkey(),
+    let recipient_wsol_token_ata = associated_token::get_associated_token_address(
+        recipient,
         &spl_token::native_mint::id(),
     );
 
@@ -723,11 +2867,11 @@ async fn unstake_lst_wrapped(
             liquid_unstaker::liquid_unstaker::client::accounts::LiquidUnstakeLstWithWrapped {
                 pool: *unstake_pool_id,
                 sol_vault: unstake_pool_info.sol_vault,
-                token_program: spl_token::id(),
-                payer: wallet_keypair.pubkey(),
-                user_transfer_authority: wallet_keypair.pubkey(),
-                user_lst_account: wallet_lst_token_ata,
-                user_sol_account: wallet_wsol_token_ata,
+                token_program: spl_stake_pool_state.token_program_id,
+                payer: payer.pubkey(),
+                user_transfer_authority: authority.pubkey(),
+                user_lst_account,
+                user_sol_account: recipient_wsol_token_ata,
                 manager_fee_account: unstake_pool_info.manager_fee_account,
                 stake_pool: spl_stake_pool_address,
                 stake_pool_validator_list: spl_stake_pool_state.validator_list,
@@ -759,14 +2903,14 @@ async fn unstake_lst_wrapped(
 
             builder.args(liquid_unstaker::liquid_unstaker::client::args::LiquidUnstakeLstWithWrappedSeed {
                     lst_amounts,
-                    minimum_lamports_out: None,
+                    minimum_lamports_out,
                     stake_account_seed: stake_account_seed
                 })
                 .instructions()?
         } else {
             builder.args(liquid_unstaker::liquid_unstaker::client::args::LiquidUnstakeLstWithWrapped {
                     lst_amounts,
-                    minimum_lamports_out: None,
+                    minimum_lamports_out,
                 })
                 .instructions()?
         };
@@ -778,7 +2922,7 @@ async fn unstake_lst_wrapped(
             .iter()
             .map(|stake_account_keypair| {
                 create_account(
-                    &wallet_keypair.pubkey(),
+                    &payer.pubkey(),
                     &stake_account_keypair.pubkey(),
                     solana_sdk::rent::Rent::default()
                         .minimum_balance(solana_sdk::stake::state::StakeStateV2::size_of()),
@@ -791,12 +2935,85 @@ async fn unstake_lst_wrapped(
         instructions.splice(0..0, create_instructions);
     }
 
+    // wSOL the recipient already held, None when the wSOL account does not exist yet
+    let recipient_wsol_amount = match rpc.get_account(&recipient_wsol_token_ata).await {
+        Ok(account) => Some(token::unpack_token_account(&account.data)?.amount),
+        Err(_) => None,
+    };
+
+    // The unstake should not fail because the recipient never held wSOL
+    if recipient_wsol_amount.is_none() {
+        instructions.insert(
+            0,
+            associated_token::spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                &payer.pubkey(),
+                recipient,
+                &spl_token::native_mint::id(),
+                &spl_token::id(),
+            ),
+        );
+    }
+
+    instructions.splice(0..0, approve_instructions);
+
+    if options.unwrap {
+        if !is_unstake_signer(recipient, payer, authority, options) {
+            return Err(anyhow::anyhow!(
+                "Unwrapping requires the recipient {} to sign the transaction",
+                recipient
+            ));
+        }
+
+        match recipient_wsol_amount {
+            // Closing the wSOL account would also unwrap the wSOL the recipient held before the unstake
+            Some(held_amount) if held_amount > 0 => println!(
+                "Not unwrapping: the wSOL account {} already holds {} wSOL, the unstaked SOL stays wrapped",
+                recipient_wsol_token_ata, held_amount
+            ),
+            // Closing the wSOL account moves all of its lamports, i.e. the unstaked SOL and the rent, to the recipient
+            _ => instructions.push(spl_token::instruction::close_account(
+                &spl_token::id(),
+                &recipient_wsol_token_ata,
+                recipient,
+                recipient,
+                &[],
+            )?),
+        }
+    }
+
+    instructions.extend(get_close_empty_lst_account_instructions(
+        payer,
+        authority,
+        &spl_stake_pool_state.token_program_id,
+        &user_lst_account,
+        &user_lst_account_state,
+        amount,
+        options,
+    )?);
+
+    if let Some(compute_unit_price) = options.compute_unit_price {
+        instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_price(compute_unit_price));
+    }
+
     instructions.insert(0, solana_sdk::compute_budget::ComputeBudgetInstruction::set_compute_unit_limit(1_000_000));
 
     // Build transaction
     let recent_blockhash = rpc.get_latest_blockhash().await?;
 
-    let mut signers = vec![wallet_keypair];
+    // The authority only signs here if we hold its keypair, otherwise it has to co-sign the transaction later
+    let mut signers = vec![payer];
+
+    if let PubkeyOrKeypair::Keypair(authority) = authority {
+        if authority.pubkey() != payer.pubkey() {
+            signers.push(authority);
+        }
+    }
+
+    if let Some(approve_owner) = options.approve_owner {
+        if signers.iter().all(|signer| signer.pubkey() != approve_owner.pubkey()) {
+            signers.push(approve_owner);
+        }
+    }
 
     if !new_stake_account_as_pda {
         
@@ -809,24 +3026,153 @@ async fn unstake_lst_wrapped(
         }
     }
 
-    let tx = Transaction::new_signed_with_payer(
-        &instructions,
-        Some(&wallet_keypair.pubkey()),
-        &signers,
-        recent_blockhash,
-    );
+    let mut tx = Transaction::new_with_payer(&instructions, Some(&payer.pubkey()));
+    tx.try_partial_sign(&signers, recent_blockhash)?;
 
-    // Send or simulate the transaction
-    send_or_simulate_transaction(&program.rpc(), &tx, simulate, 
-        Some(vec![
-            wallet_keypair.pubkey(), 
-            wallet_wsol_token_ata,
-            unstake_pool_info.sol_vault,
-            unstake_pool_info.manager_fee_account,
-            new_stake_accounts[0].pubkey(),
-        ])).await?;
+    let mut simulation_accounts_of_interest = vec![
+        payer.pubkey(),
+        recipient_wsol_token_ata,
+        unstake_pool_info.sol_vault,
+        unstake_pool_info.manager_fee_account,
+        new_stake_accounts[0].pubkey(),
+    ];
 
-    Ok(())
+    if *recipient != payer.pubkey() {
+        simulation_accounts_of_interest.insert(1, *recipient);
+    }
+
+    Ok((tx, simulation_accounts_of_interest))
+}
+
+/// Get the LST token account to unstake from, making sure the transfer authority is allowed to transfer `amount`
+/// tokens from it. Returns the account and its state together with the approve instructions to prepend, if any
+async fn get_user_lst_account(
+    rpc: &RpcClient,
+    authority: &PubkeyOrKeypair,
+    mint: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+    options: &UnstakeLstOptions<'_>,
+) -> Result<(Pubkey, spl_token_2022::state::Account, Vec<Instruction>)> {
+    // When the owner approves the authority, the LST is taken from the owner's ATA by default
+    let owner = options
+        .approve_owner
+        .map(|approve_owner| approve_owner.pubkey())
+        .unwrap_or(authority.pubkey());
+    let user_lst_account = options
+        .source_lst_account
+        .unwrap_or(associated_token::get_associated_token_address_with_program_id(
+            &owner,
+            mint,
+            token_program_id,
+        ));
+
+    let user_lst_account_state = rpc
+        .get_account(&user_lst_account)
+        .await
+        .map_err(|_| anyhow::anyhow!("LST token account {} not found", user_lst_account))
+        .and_then(|account| token::unpack_token_account(&account.data))?;
+
+    if user_lst_account_state.mint != *mint {
+        return Err(anyhow::anyhow!("LST token account {} is not an account for mint {}", user_lst_account, mint));
+    }
+
+    if user_lst_account_state.amount < amount {
+        return Err(anyhow::anyhow!(
+            "LST token account {} only holds {} tokens",
+            user_lst_account,
+            user_lst_account_state.amount
+        ));
+    }
+
+    if user_lst_account_state.owner == authority.pubkey() {
+        return Ok((user_lst_account, user_lst_account_state, vec![]));
+    }
+
+    // The owner is around to sign, so the authority can be approved as delegate in the same transaction
+    if let Some(approve_owner) = options.approve_owner {
+        if user_lst_account_state.owner != approve_owner.pubkey() {
+            return Err(anyhow::anyhow!(
+                "LST token account {} is owned by {}, not by {}",
+                user_lst_account,
+                user_lst_account_state.owner,
+                approve_owner.pubkey()
+            ));
+        }
+
+        let approve_instruction = spl_token_2022::instruction::approve(
+            token_program_id,
+            &user_lst_account,
+            &authority.pubkey(),
+            &approve_owner.pubkey(),
+            &[],
+            amount,
+        )?;
+
+        return Ok((user_lst_account, user_lst_account_state, vec![approve_instruction]));
+    }
+
+    let delegated_amount = match user_lst_account_state.delegate {
+        COption::Some(delegate) if delegate == authority.pubkey() => user_lst_account_state.delegated_amount,
+        _ => 0,
+    };
+
+    if delegated_amount < amount {
+        return Err(anyhow::anyhow!(
+            "{} is only allowed to transfer {} tokens from LST token account {}",
+            authority.pubkey(),
+            delegated_amount,
+            user_lst_account
+        ));
+    }
+
+    Ok((user_lst_account, user_lst_account_state, vec![]))
+}
+
+/// Get the instruction closing the source LST token account if the unstake empties it and
+/// [`UnstakeLstOptions::close_empty_lst_account`] is set. The rent goes back to the owner of the account
+fn get_close_empty_lst_account_instructions(
+    payer: &Keypair,
+    authority: &PubkeyOrKeypair,
+    token_program_id: &Pubkey,
+    user_lst_account: &Pubkey,
+    user_lst_account_state: &spl_token_2022::state::Account,
+    amount: u64,
+    options: &UnstakeLstOptions<'_>,
+) -> Result<Vec<Instruction>> {
+    if !options.close_empty_lst_account || user_lst_account_state.amount != amount {
+        return Ok(vec![]);
+    }
+
+    let owner = user_lst_account_state.owner;
+
+    if !is_unstake_signer(&owner, payer, authority, options) {
+        return Err(anyhow::anyhow!(
+            "Closing LST token account {} requires its owner {} to sign the transaction",
+            user_lst_account,
+            owner
+        ));
+    }
+
+    Ok(vec![spl_token_2022::instruction::close_account(
+        token_program_id,
+        user_lst_account,
+        &owner,
+        &owner,
+        &[],
+    )?])
+}
+
+/// Whether the given account signs the unstake transaction, now or when co-signing it later
+fn is_unstake_signer(
+    account: &Pubkey,
+    payer: &Keypair,
+    authority: &PubkeyOrKeypair,
+    options: &UnstakeLstOptions<'_>,
+) -> bool {
+    *account == payer.pubkey()
+        || *account == authority.pubkey()
+        || options.approve_owner.is_some_and(|approve_owner| *account == approve_owner.pubkey())
 }
 
 async fn get_stake_pool_program_for_lst_mint(
@@ -846,6 +3192,17 @@ async fn get_stake_pool_program_for_lst_mint(
 }
 
 async fn get_stake_pool_mints(rpc: &RpcClient, program_id: &Pubkey) -> Result<Vec<Pubkey>> {
+    let spl_stake_pools = get_stake_pools(rpc, program_id)
+        .await?
+        .into_iter()
+        .map(|(_pubkey, pool_state)| pool_state.pool_mint)
+        .collect::<Vec<_>>();
+
+    Ok(spl_stake_pools)
+}
+
+/// Function to get all SPL Stake Pools (address and state) owned by the given stake pool program
+async fn get_stake_pools(rpc: &RpcClient, program_id: &Pubkey) -> Result<Vec<(Pubkey, StakePool)>> {
     let spl_stake_pools = rpc
         .get_program_accounts_with_config(
             &program_id,
@@ -860,22 +3217,42 @@ async fn get_stake_pool_mints(rpc: &RpcClient, program_id: &Pubkey) -> Result<Ve
         )
         .await?
         .into_iter()
-        .map(|(_pubkey, account)| {
+        .map(|(pubkey, account)| {
             let mut data = account.data.as_slice();
             let pool_state = spl_stake_pool::state::StakePool::deserialize(&mut data).unwrap();
 
-            pool_state.pool_mint
+            (pubkey, pool_state)
         })
         .collect::<Vec<_>>();
 
     Ok(spl_stake_pools)
 }
+
 async fn send_or_simulate_transaction(
     rpc: &RpcClient,
     tx: &Transaction,
     simulate: bool,
     simulation_accounts_of_interest: Option<Vec<Pubkey>>,
 ) -> Result<()> {
+    if simulate {
+        send_or_simulate_transaction_checked(rpc, tx, true, simulation_accounts_of_interest).await?;
+    } else if let Err(err) = send_or_simulate_transaction_checked(rpc, tx, false, None).await {
+        // A failed transaction is reported but does not fail the command
+        println!("{}", err);
+    }
+
+    Ok(())
+}
+
+/// Send the transaction, or simulate it and print the balance changes of the accounts of interest.
+/// Returns the signature if the transaction was sent and confirmed, and an error if sending it failed, for the
+/// commands that send several transactions and have to know which ones landed
+async fn send_or_simulate_transaction_checked(
+    rpc: &RpcClient,
+    tx: &Transaction,
+    simulate: bool,
+    simulation_accounts_of_interest: Option<Vec<Pubkey>>,
+) -> Result<Option<Signature>> {
     if simulate {
 
         let simulation_accounts_of_interest = simulation_accounts_of_interest.unwrap_or(vec![]);
@@ -924,16 +3301,83 @@ async fn send_or_simulate_transaction(
 
         match result {
             Err(err) => {
-                println!("Transaction failed: {:#?}", err);
+                return Err(anyhow::anyhow!("Transaction failed: {:#?}", err));
             }
             Ok(signature) => {
                 println!("Signature: {:?}", signature);
+
+                return Ok(Some(signature));
             }
         }
     }
+    Ok(None)
+}
+
+/// The wrapped unstake instruction takes a single token program for both the LST and the wSOL, so it only supports
+/// LSTs of the SPL Token program
+fn check_wrapped_unstake_token_program(spl_stake_pool_state: &spl_stake_pool::state::StakePool) -> Result<()> {
+    if spl_stake_pool_state.token_program_id != spl_token::id() {
+        return Err(anyhow::anyhow!(
+            "Unstaking to wSOL is only supported for LSTs of the SPL Token program, use unstake-lst instead"
+        ));
+    }
+
     Ok(())
 }
 
+/// Minimum lamports out for the given quote when accepting `slippage_bps` basis points of slippage
+fn apply_slippage(quote: i64, slippage_bps: u16) -> u64 {
+    (quote.max(0) as u128 * 10_000u128.saturating_sub(slippage_bps as u128) / 10_000) as u64
+}
+
+/// Serialize a (partially) signed transaction to base64, e.g. to hand it over to another signer
+fn serialize_transaction_base64(tx: &Transaction) -> Result<String> {
+    Ok(base64::engine::general_purpose::STANDARD.encode(bincode::serialize(tx)?))
+}
+
+/// Function to get the unstake pool of the program that gives the best output for the unstake or quote command
+async fn get_best_pool_for_unstake(
+    program: &anchor_client::Program<Rc<Keypair>>,
+    matches: &clap::ArgMatches,
+    new_stake_account_as_pda: bool,
+) -> Result<Pubkey> {
+    let (wrapped, arg_matches) = match matches.subcommand() {
+        Some(("quote-unstake-lst", arg_matches)) | Some(("unstake-lst", arg_matches)) => (false, arg_matches),
+        Some(("quote-unstake-lst-wrapped", arg_matches)) | Some(("unstake-lst-wrapped", arg_matches)) => {
+            (true, arg_matches)
+        }
+        _ => return Err(anyhow::anyhow!("--best-pool is only supported by the quote-unstake-lst, unstake-lst, quote-unstake-lst-wrapped and unstake-lst-wrapped commands")),
+    };
+
+    let rpc = program.rpc();
+    let mint = Pubkey::from_str(arg_matches.get_one::<String>("mint").unwrap())?;
+    let amount = *arg_matches.get_one::<u64>("amount").unwrap();
+
+    let spl_stake_pool_program_id = get_stake_pool_program_for_lst_mint(&rpc, &mint)
+        .await?
+        .ok_or(anyhow::anyhow!("Could not find a supported stake pool for the given mint"))?;
+    let (_, spl_stake_pool_state) = get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
+
+    if wrapped {
+        check_wrapped_unstake_token_program(&spl_stake_pool_state)?;
+    }
+
+    let unstake_pools = pools::get_unstake_pools(&rpc, &program.id()).await?;
+
+    let (pool_id, _, quote) = pools::find_best_pool(
+        &unstake_pools,
+        &spl_stake_pool_state,
+        amount,
+        wrapped,
+        new_stake_account_as_pda,
+    )
+    .ok_or(anyhow::anyhow!("None of the {} unstake pools can take this unstake", unstake_pools.len()))?;
+
+    println!("Best of {} pools: {} with {} lamports out", unstake_pools.len(), pool_id, quote);
+
+    Ok(pool_id)
+}
+
 /// Function to get the SPL Stake Pool info for the given pool (LST) mint, uses the GetProgramAccounts RPC call
 async fn get_stake_pool_for_lst_mint(
     rpc: &RpcClient,
@@ -978,12 +3422,38 @@ async fn get_stake_pool_for_lst_mint(
     Ok((spl_stake_pool_address, spl_stake_pool_state))
 }
 
+/// Breakdown of an LST unstake quote
+pub struct UnstakeQuote {
+    /// LST tokens unstaked
+    pub pool_tokens: u64,
+    /// LST tokens taken by the stake pool as stake withdrawal fee
+    pub stake_pool_fee_tokens: u64,
+    /// Lamports of stake taken over by the unstake pool, including the rent of the new stake account
+    pub lamports_unstaked: u64,
+    pub fee: Fee,
+    /// Lamports received
+    pub lamports_out: i64,
+    /// Fee over the whole unstake including the manager fee, in percent
+    pub effective_fee_pct: f64,
+    /// Fee on the last lamport of the unstake including the manager fee, in percent
+    pub marginal_fee_pct: f64,
+}
+
 /// Function to get the amount of lamports that would be received by unstaking the given amount of LST tokens
 pub fn quote_lst_unstake(
     stake_pool_state: &StakePool,
     liquid_unstake_pool_state: &liquid_unstaker::liquid_unstaker::accounts::Pool,
     pool_tokens: u64,
 ) -> Result<i64> {
+    Ok(quote_lst_unstake_breakdown(stake_pool_state, liquid_unstake_pool_state, pool_tokens)?.lamports_out)
+}
+
+/// Function to get the full quote of unstaking the given amount of LST tokens, with the fees charged
+pub fn quote_lst_unstake_breakdown(
+    stake_pool_state: &StakePool,
+    liquid_unstake_pool_state: &liquid_unstaker::liquid_unstaker::accounts::Pool,
+    pool_tokens: u64,
+) -> Result<UnstakeQuote> {
     let pool_tokens_fee = stake_pool_state
         .calc_pool_tokens_stake_withdrawal_fee(pool_tokens)
         .unwrap() as u64;
@@ -1027,7 +3497,29 @@ pub fn quote_lst_unstake(
 
     let amount_out = total_amount_to_unstake as i64 - fee_amount as i64 - stake_account_rent as i64;
 
-    return Ok(amount_out);
+    // The rate charged on the next lamport, once this unstake has left the vault
+    let marginal_base_fee_pct_bps = Fee::calculate_base_fee(
+        liquid_unstake_pool_state,
+        liquid_unstake_pool_state.sol_vault_lamports - total_amount_to_unstake,
+        0,
+    )?;
+    let total_fee_pct = |base_fee_pct_bps: f64| {
+        base_fee_pct_bps * (100 + liquid_unstake_pool_state.manager_fee_pct as u64) as f64 / FEE_PCT_DIVISOR as f64
+    };
+
+    Ok(UnstakeQuote {
+        pool_tokens,
+        stake_pool_fee_tokens: pool_tokens_fee,
+        lamports_unstaked: total_amount_to_unstake,
+        lamports_out: amount_out,
+        effective_fee_pct: if total_amount_to_unstake > 0 {
+            fee_amount as f64 * 100.0 / total_amount_to_unstake as f64
+        } else {
+            0.0
+        },
+        marginal_fee_pct: total_fee_pct(marginal_base_fee_pct_bps as f64),
+        fee,
+    })
 }
 
 
@@ -1088,6 +3580,7 @@ pub fn quote_lst_unstake_wrapped(
 }
 
 fn get_unstake_accounts(
+    unstake_program_id: &Pubkey,
     stake_pool_program: &Pubkey,
     stake_pool_address: &Pubkey,
     stake_pool_state: &spl_stake_pool::state::StakePool,
@@ -1210,7 +3703,7 @@ fn get_unstake_accounts(
                     b"stake_account_info",
                     &stake_account_keypair.pubkey().to_bytes(),
                 ],
-                &liquid_unstaker::liquid_unstaker::ID_CONST,
+                unstake_program_id,
             )
             .0
         })
@@ -1225,13 +3718,15 @@ fn get_unstake_accounts(
 }
 
 
+#[allow(clippy::too_many_arguments)]
 fn get_unstake_accounts_with_new_stake_account_as_pda(
+    unstake_program_id: &Pubkey,
     stake_pool_program: &Pubkey,
     stake_pool_address: &Pubkey,
     stake_pool_state: &spl_stake_pool::state::StakePool,
     stake_pool_validator_list: &spl_stake_pool::state::ValidatorList,
     stake_account_seed: u64,
-    token_transfer_authority: &Pubkey,
+    payer: &Pubkey,
     amount_in: u64,
 ) -> Result<(Vec<u64>, Vec<Pubkey>, Vec<PubkeyOrKeypair>, Vec<Pubkey>)> {
     #[derive(Clone)]
@@ -1344,12 +3839,12 @@ fn get_unstake_accounts_with_new_stake_account_as_pda(
         .map(|(i, _)| {
             // PDA for the new stake account is derived from
             //
-            //  1. The payer, which we assume is SwapParams.token_transfer_authority
+            //  1. The payer of the unstake transaction
             //  2. The seed, which is total_deactivating_stake + i
             //
             let (pubkey, _) = Pubkey::find_program_address(
-                &[b"stake_account", token_transfer_authority.as_ref(), (stake_account_seed + i as u64).to_le_bytes().as_ref()],
-                &liquid_unstaker::liquid_unstaker::ID_CONST,
+                &[b"stake_account", payer.as_ref(), (stake_account_seed + i as u64).to_le_bytes().as_ref()],
+                unstake_program_id,
             );
 
             println!("Derived new stake account PDA: {}", pubkey);
@@ -1365,7 +3860,7 @@ fn get_unstake_accounts_with_new_stake_account_as_pda(
                     b"stake_account_info",
                     stake_account_keypair.as_ref(),
                 ],
-                &liquid_unstaker::liquid_unstaker::ID_CONST,
+                unstake_program_id,
             )
             .0
         })
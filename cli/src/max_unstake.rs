@@ -0,0 +1,169 @@
+use std::cmp::Reverse;
+
+use anyhow::Result;
+use itertools::Itertools;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use spl_stake_pool::state::{StakePool, StakeStatus, ValidatorList};
+
+use crate::{quote_lst_unstake_breakdown, UnstakeQuote};
+
+/// Maximum number of stake accounts a single unstake transaction can withdraw from
+pub const MAX_STAKE_ACCOUNTS_PER_UNSTAKE: usize = 5;
+
+/// Constraints for the largest unstake search, None means unconstrained
+#[derive(Default)]
+pub struct MaxUnstakeConstraints {
+    /// Maximum fee over the whole unstake, in percent
+    pub max_effective_fee_pct: Option<f64>,
+    /// Maximum fee on the last lamport of the unstake, in percent
+    pub max_marginal_fee_pct: Option<f64>,
+    /// Maximum LST tokens to unstake, e.g. the wallet balance
+    pub max_pool_tokens: Option<u64>,
+}
+
+/// Function to get the LST tokens that can be withdrawn from the stake pool in a single unstake transaction,
+/// picking the stake accounts in the same order as the unstake does: preferred validator first, then largest first
+pub fn get_max_pool_tokens_for_stake_accounts(
+    stake_pool_state: &StakePool,
+    stake_pool_validator_list: &ValidatorList,
+    max_stake_accounts: usize,
+) -> u64 {
+    let fee = &stake_pool_state.stake_withdrawal_fee;
+    let inverse_fee_numerator = fee.denominator - fee.numerator;
+
+    stake_pool_validator_list
+        .validators
+        .iter()
+        .filter(|validator_info| validator_info.status == StakeStatus::Active.into())
+        .map(|validator_info| {
+            let is_preferred = stake_pool_state.preferred_withdraw_validator_vote_address
+                == Some(validator_info.vote_account_address);
+
+            (is_preferred, Into::<u64>::into(validator_info.active_stake_lamports))
+        })
+        .filter(|(_, lamports)| *lamports != 0)
+        .sorted_by_key(|(is_preferred, lamports)| (Reverse(*is_preferred), Reverse(*lamports)))
+        .take(max_stake_accounts)
+        .map(|(_, lamports)| {
+            let mut pool_tokens = stake_pool_state.calc_pool_tokens_for_deposit(lamports).unwrap_or(0) as u128;
+
+            if inverse_fee_numerator != 0 {
+                pool_tokens = pool_tokens * fee.denominator as u128 / inverse_fee_numerator as u128;
+            }
+
+            pool_tokens as u64
+        })
+        .fold(0u64, |total, pool_tokens| total.saturating_add(pool_tokens))
+}
+
+/// Function to find the largest LST unstake satisfying the constraints, the vault liquidity and the stake account
+/// limit of a single transaction. The fee only grows with the unstake size, so a binary search finds the largest
+/// amount
+pub fn find_max_unstake(
+    stake_pool_state: &StakePool,
+    stake_pool_validator_list: &ValidatorList,
+    pool: &Pool,
+    constraints: &MaxUnstakeConstraints,
+) -> Result<UnstakeQuote> {
+    let upper_bound = get_max_pool_tokens_for_stake_accounts(
+        stake_pool_state,
+        stake_pool_validator_list,
+        MAX_STAKE_ACCOUNTS_PER_UNSTAKE,
+    )
+    .min(constraints.max_pool_tokens.unwrap_or(u64::MAX));
+
+    let satisfies_constraints = |pool_tokens: u64| -> Option<UnstakeQuote> {
+        let quote = quote_lst_unstake_breakdown(stake_pool_state, pool, pool_tokens).ok()?;
+
+        let effective_fee_ok = constraints
+            .max_effective_fee_pct
+            .is_none_or(|max_fee_pct| quote.effective_fee_pct <= max_fee_pct);
+        let marginal_fee_ok = constraints
+            .max_marginal_fee_pct
+            .is_none_or(|max_fee_pct| quote.marginal_fee_pct <= max_fee_pct);
+
+        (quote.lamports_out > 0 && effective_fee_ok && marginal_fee_ok).then_some(quote)
+    };
+
+    let mut low = 0;
+    let mut high = upper_bound;
+    let mut best = None;
+
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+
+        match satisfies_constraints(mid) {
+            Some(quote) => {
+                best = Some(quote);
+                low = mid;
+            }
+            None => high = mid - 1,
+        }
+    }
+
+    best.ok_or(anyhow::anyhow!("No unstake amount satisfies the constraints"))
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+
+    use super::*;
+    use crate::test_utils::{get_test_pool, get_test_stake_pool, get_test_validator_list};
+
+    #[test]
+    fn test_get_max_pool_tokens_for_stake_accounts() {
+        let stake_pool = get_test_stake_pool();
+        let validator_list = get_test_validator_list(&[1, 6, 2, 5, 3, 4].map(|sol| sol * LAMPORTS_PER_SOL));
+
+        // The five largest validator stake accounts
+        assert_eq!(
+            get_max_pool_tokens_for_stake_accounts(&stake_pool, &validator_list, MAX_STAKE_ACCOUNTS_PER_UNSTAKE),
+            20 * LAMPORTS_PER_SOL
+        );
+    }
+
+    #[test]
+    fn test_find_max_unstake_marginal_fee_cap() {
+        let pool = get_test_pool();
+        let stake_pool = get_test_stake_pool();
+        let validator_list = get_test_validator_list(&[5_000 * LAMPORTS_PER_SOL]);
+        let constraints = MaxUnstakeConstraints {
+            max_marginal_fee_pct: Some(1.0),
+            ..Default::default()
+        };
+
+        let quote = find_max_unstake(&stake_pool, &validator_list, &pool, &constraints).unwrap();
+        assert!(quote.marginal_fee_pct <= 1.0);
+
+        // One more token crosses the cap
+        let next = quote_lst_unstake_breakdown(&stake_pool, &pool, quote.pool_tokens + 1).unwrap();
+        assert!(next.marginal_fee_pct > 1.0);
+    }
+
+    #[test]
+    fn test_find_max_unstake_constraints() {
+        let pool = get_test_pool();
+        let stake_pool = get_test_stake_pool();
+        let validator_list = get_test_validator_list(&[100 * LAMPORTS_PER_SOL]);
+
+        // Limited by the validator stake accounts
+        let quote = find_max_unstake(&stake_pool, &validator_list, &pool, &MaxUnstakeConstraints::default()).unwrap();
+        assert_eq!(quote.pool_tokens, 100 * LAMPORTS_PER_SOL);
+
+        // Limited by the balance
+        let constraints = MaxUnstakeConstraints {
+            max_pool_tokens: Some(LAMPORTS_PER_SOL),
+            ..Default::default()
+        };
+        let quote = find_max_unstake(&stake_pool, &validator_list, &pool, &constraints).unwrap();
+        assert_eq!(quote.pool_tokens, LAMPORTS_PER_SOL);
+
+        // No amount meets a cap below fee_min
+        let constraints = MaxUnstakeConstraints {
+            max_effective_fee_pct: Some(0.1),
+            ..Default::default()
+        };
+        assert!(find_max_unstake(&stake_pool, &validator_list, &pool, &constraints).is_err());
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+};
+
+use anchor_client::{
+    solana_sdk::{
+        hash::Hash,
+        pubkey::Pubkey,
+        signature::{Keypair, Signature},
+        signer::Signer,
+    },
+    Program,
+};
+use anyhow::Result;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    apply_slippage,
+    batch::{self, PendingOutcome},
+    build_unstake_lst_transaction, config, get_stake_pool_for_lst_mint, get_stake_pool_program_for_lst_mint,
+    quote_lst_unstake_breakdown, send_or_simulate_transaction, send_or_simulate_transaction_checked,
+    PubkeyOrKeypair, UnstakeLstOptions,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    /// Waiting for the fee to drop to the limit
+    Open,
+    /// The unstake was sent but it is not known yet whether it landed
+    Pending,
+    Filled,
+    Cancelled,
+    Expired,
+}
+
+/// A conditional unstake: unstake `amount` of `mint` through `pool` once the effective fee is at most `max_fee_bps`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Order {
+    pub id: u64,
+    pub pool: String,
+    pub mint: String,
+    pub amount: u64,
+    pub max_fee_bps: u32,
+    /// Wallet receiving the SOL, defaults to the wallet running the orders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    pub created_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    pub status: OrderStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blockhash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum_lamports_out: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamports_received: Option<i64>,
+    /// Last error executing the order, the order stays open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Default location of the order book, in the config directory
+pub fn get_default_order_book_path() -> Option<PathBuf> {
+    config::get_config_dir().map(|config_dir| config_dir.join("orders.json"))
+}
+
+/// Function to read the order book, a missing order book has no orders
+pub fn read_order_book(path: &Path) -> Result<Vec<Order>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|err| anyhow::anyhow!("Invalid order book {}: {}", path.display(), err))
+}
+
+/// Function to write the order book, through a temporary file so a crash never leaves half an order book behind
+pub fn write_order_book(path: &Path, orders: &[Order]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temporary_path = path.with_extension("json.tmp");
+    fs::write(&temporary_path, serde_json::to_string_pretty(orders)?)?;
+    fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+/// Function to lock the order book until the returned lock file is dropped, so the read-modify-writes of concurrent
+/// processes, e.g. `order add` while the orders run, happen one after the other instead of losing each other's changes
+fn lock_order_book(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let lock_path = path.with_extension("json.lock");
+    let lock_file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|err| anyhow::anyhow!("Failed to open the order book lock {}: {}", lock_path.display(), err))?;
+    lock_file
+        .lock()
+        .map_err(|err| anyhow::anyhow!("Failed to lock the order book {}: {}", lock_path.display(), err))?;
+
+    Ok(lock_file)
+}
+
+/// Function to change a single order. The order book is locked and read again right before, so changes made by
+/// another process in the meantime, e.g. a cancellation while the orders run, are kept
+pub fn update_order(path: &Path, id: u64, update: impl FnOnce(&mut Order) -> Result<()>) -> Result<Order> {
+    let _lock = lock_order_book(path)?;
+    let mut orders = read_order_book(path)?;
+    let order = orders
+        .iter_mut()
+        .find(|order| order.id == id)
+        .ok_or(anyhow::anyhow!("Order {} not found in {}", id, path.display()))?;
+
+    update(order)?;
+    let order = order.clone();
+
+    write_order_book(path, &orders)?;
+
+    Ok(order)
+}
+
+/// Function to change a single order only while it still has the given status, so an order that another process
+/// moved on in the meantime, e.g. cancelled while the orders run, is left as is. Returns None in that case
+pub fn update_order_with_status(
+    path: &Path,
+    id: u64,
+    status: OrderStatus,
+    update: impl FnOnce(&mut Order),
+) -> Result<Option<Order>> {
+    let _lock = lock_order_book(path)?;
+    let mut orders = read_order_book(path)?;
+    let order = orders
+        .iter_mut()
+        .find(|order| order.id == id)
+        .ok_or(anyhow::anyhow!("Order {} not found in {}", id, path.display()))?;
+
+    if order.status != status {
+        return Ok(None);
+    }
+
+    update(order);
+    let order = order.clone();
+
+    write_order_book(path, &orders)?;
+
+    Ok(Some(order))
+}
+
+/// Function to add an open order to the order book, it gets the next free id
+#[allow(clippy::too_many_arguments)]
+pub fn add_order(
+    path: &Path,
+    pool: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    max_fee_bps: u32,
+    recipient: Option<Pubkey>,
+    expires_at: Option<i64>,
+    now: i64,
+) -> Result<Order> {
+    let _lock = lock_order_book(path)?;
+    let mut orders = read_order_book(path)?;
+
+    let order = Order {
+        id: orders.iter().map(|order| order.id + 1).max().unwrap_or(1),
+        pool: pool.to_string(),
+        mint: mint.to_string(),
+        amount,
+        max_fee_bps,
+        recipient: recipient.map(|recipient| recipient.to_string()),
+        created_at: now,
+        expires_at,
+        status: OrderStatus::Open,
+        signature: None,
+        blockhash: None,
+        minimum_lamports_out: None,
+        lamports_received: None,
+        error: None,
+    };
+
+    orders.push(order.clone());
+    write_order_book(path, &orders)?;
+
+    Ok(order)
+}
+
+/// Function to cancel an open order
+pub fn cancel_order(path: &Path, id: u64) -> Result<Order> {
+    update_order(path, id, |order| {
+        if order.status != OrderStatus::Open {
+            return Err(anyhow::anyhow!(
+                "Order {} is {:?}, only open orders can be cancelled",
+                order.id,
+                order.status
+            ));
+        }
+
+        order.status = OrderStatus::Cancelled;
+
+        Ok(())
+    })
+}
+
+/// Run every order of the order book once: expire the open orders past their expiry, settle the pending ones and
+/// execute the open orders whose effective fee is at most their limit, with the minimum lamports out set from the
+/// quote that triggered them. `stake_pool_programs` caches the stake pool program of every mint between runs
+#[allow(clippy::too_many_arguments)]
+pub async fn run_orders(
+    program: &Program<Rc<Keypair>>,
+    fee_payer: &Keypair,
+    wallet_keypair: &Keypair,
+    path: &Path,
+    stake_pool_programs: &mut HashMap<Pubkey, Option<Pubkey>>,
+    simulate: bool,
+    new_stake_account_as_pda: bool,
+    compute_unit_price: Option<u64>,
+    slippage_bps: Option<u16>,
+    now: i64,
+) -> Result<()> {
+    let rpc = program.rpc();
+    let authority = PubkeyOrKeypair::Keypair(wallet_keypair.insecure_clone());
+
+    for order in read_order_book(path)? {
+        let recipient = order
+            .recipient
+            .as_deref()
+            .map(Pubkey::from_str)
+            .transpose()?
+            .unwrap_or(wallet_keypair.pubkey());
+
+        match order.status {
+            OrderStatus::Open if order.expires_at.is_some_and(|expires_at| expires_at <= now) => {
+                let expired = update_order_with_status(path, order.id, OrderStatus::Open, |order| {
+                    order.status = OrderStatus::Expired;
+                })?;
+
+                if expired.is_some() {
+                    println!("Order {} expired", order.id);
+                }
+
+                continue;
+            }
+            OrderStatus::Open => {}
+            OrderStatus::Pending => {
+                let (Some(signature), Some(blockhash)) = (&order.signature, &order.blockhash) else {
+                    continue;
+                };
+                let signature = Signature::from_str(signature)?;
+
+                match batch::resolve_pending(&rpc, &signature, &Hash::from_str(blockhash)?).await? {
+                    PendingOutcome::Landed => {
+                        let lamports_received = batch::get_lamports_received(&rpc, &signature, &recipient).await.ok();
+
+                        update_order_with_status(path, order.id, OrderStatus::Pending, |order| {
+                            order.status = OrderStatus::Filled;
+                            order.lamports_received = lamports_received;
+                        })?;
+
+                        println!("Order {} filled with signature {}", order.id, signature);
+                    }
+                    PendingOutcome::StillPending => {}
+                    PendingOutcome::Failed(err) => reopen_order(path, order.id, OrderStatus::Pending, err)?,
+                    PendingOutcome::Dropped => {
+                        reopen_order(path, order.id, OrderStatus::Pending, "Transaction dropped".to_string())?
+                    }
+                }
+
+                continue;
+            }
+            OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Expired => continue,
+        }
+
+        let pool_id = Pubkey::from_str(&order.pool)?;
+        let mint = Pubkey::from_str(&order.mint)?;
+
+        if let std::collections::hash_map::Entry::Vacant(entry) = stake_pool_programs.entry(mint) {
+            entry.insert(get_stake_pool_program_for_lst_mint(&rpc, &mint).await?);
+        }
+
+        let Some(spl_stake_pool_program_id) = stake_pool_programs[&mint] else {
+            reopen_order(
+                path,
+                order.id,
+                OrderStatus::Open,
+                "Could not find a supported stake pool for the given mint".to_string(),
+            )?;
+            continue;
+        };
+
+        // Fetched for every order, an order filled before changes the vault of the pool
+        let unstake_pool_info = program.account::<Pool>(pool_id).await?;
+        let (_, spl_stake_pool_state) = get_stake_pool_for_lst_mint(&rpc, &mint, &spl_stake_pool_program_id).await?;
+
+        let quote = match quote_lst_unstake_breakdown(&spl_stake_pool_state, &unstake_pool_info, order.amount) {
+            Ok(quote) => quote,
+            Err(err) => {
+                println!("Order {}: {}", order.id, err);
+                continue;
+            }
+        };
+
+        let fee_bps = quote.effective_fee_pct * 100.0;
+
+        if fee_bps > order.max_fee_bps as f64 {
+            println!(
+                "Order {}: effective fee {:.2} bps is above the limit of {} bps",
+                order.id, fee_bps, order.max_fee_bps
+            );
+            continue;
+        }
+
+        let minimum_lamports_out = match slippage_bps {
+            Some(slippage_bps) => apply_slippage(quote.lamports_out, slippage_bps),
+            None => quote.lamports_out.max(0) as u64,
+        };
+
+        println!(
+            "Order {}: effective fee {:.2} bps is within the limit of {} bps, unstaking {} tokens for at least {} lamports",
+            order.id, fee_bps, order.max_fee_bps, order.amount, minimum_lamports_out
+        );
+
+        let built = build_unstake_lst_transaction(
+            program,
+            &pool_id,
+            fee_payer,
+            &authority,
+            &spl_stake_pool_program_id,
+            &mint,
+            &unstake_pool_info,
+            order.amount,
+            new_stake_account_as_pda,
+            &UnstakeLstOptions {
+                recipient: Some(recipient),
+                minimum_lamports_out: Some(minimum_lamports_out),
+                compute_unit_price,
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let (tx, simulation_accounts_of_interest) = match built {
+            Ok(built) => built,
+            Err(err) => {
+                reopen_order(path, order.id, OrderStatus::Open, err.to_string())?;
+                continue;
+            }
+        };
+
+        if simulate {
+            send_or_simulate_transaction(&rpc, &tx, true, Some(simulation_accounts_of_interest)).await?;
+            continue;
+        }
+
+        // Record the signature before sending, so a restarted run can tell whether it landed. The order is only sent
+        // if it is still open, it may have been cancelled while it was quoted
+        let pending = update_order_with_status(path, order.id, OrderStatus::Open, |order| {
+            order.status = OrderStatus::Pending;
+            order.signature = Some(tx.signatures[0].to_string());
+            order.blockhash = Some(tx.message.recent_blockhash.to_string());
+            order.minimum_lamports_out = Some(minimum_lamports_out);
+        })?;
+
+        if pending.is_none() {
+            println!("Order {} is no longer open, it is not sent", order.id);
+            continue;
+        }
+
+        let outcome = match send_or_simulate_transaction_checked(&rpc, &tx, false, None).await {
+            Ok(_) => PendingOutcome::Landed,
+            Err(err) => match batch::resolve_pending(&rpc, &tx.signatures[0], &tx.message.recent_blockhash).await {
+                Ok(PendingOutcome::Dropped) | Err(_) => PendingOutcome::Failed(err.to_string()),
+                Ok(outcome) => outcome,
+            },
+        };
+
+        match outcome {
+            PendingOutcome::Landed => {
+                let lamports_received = batch::get_lamports_received(&rpc, &tx.signatures[0], &recipient).await.ok();
+
+                update_order_with_status(path, order.id, OrderStatus::Pending, |order| {
+                    order.status = OrderStatus::Filled;
+                    order.lamports_received = lamports_received;
+                    order.error = None;
+                })?;
+
+                println!("Order {} filled with signature {}", order.id, tx.signatures[0]);
+            }
+            PendingOutcome::StillPending => {}
+            PendingOutcome::Failed(err) => reopen_order(path, order.id, OrderStatus::Pending, err)?,
+            PendingOutcome::Dropped => {
+                reopen_order(path, order.id, OrderStatus::Pending, "Transaction dropped".to_string())?
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Function to put an order back to open after it failed to execute, it is tried again on the next run. `status` is
+/// the status the order failed in, an order that moved on in the meantime is left as is
+fn reopen_order(path: &Path, id: u64, status: OrderStatus, error: String) -> Result<()> {
+    let reopened = update_order_with_status(path, id, status, |order| {
+        order.status = OrderStatus::Open;
+        order.signature = None;
+        order.blockhash = None;
+        order.error = Some(error.clone());
+    })?;
+
+    if reopened.is_some() {
+        println!("Order {} failed, it stays open: {}", id, error);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Order book in a fresh temporary directory, removed when dropped
+    struct TestOrderBook(PathBuf);
+
+    impl TestOrderBook {
+        fn new(name: &str) -> TestOrderBook {
+            let dir = std::env::temp_dir().join(format!("liquid-unstaker-orders-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+
+            TestOrderBook(dir.join("orders.json"))
+        }
+
+        fn add_order(&self) -> Order {
+            add_order(&self.0, &Pubkey::new_unique(), &Pubkey::new_unique(), 1_000, 50, None, None, 0).unwrap()
+        }
+    }
+
+    impl Drop for TestOrderBook {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_add_and_cancel_orders() {
+        let order_book = TestOrderBook::new("cancel");
+
+        assert!(read_order_book(&order_book.0).unwrap().is_empty());
+        assert_eq!(order_book.add_order().id, 1);
+        assert_eq!(order_book.add_order().id, 2);
+
+        assert_eq!(cancel_order(&order_book.0, 1).unwrap().status, OrderStatus::Cancelled);
+        // Only open orders can be cancelled
+        assert!(cancel_order(&order_book.0, 1).is_err());
+        assert!(cancel_order(&order_book.0, 3).is_err());
+
+        let orders = read_order_book(&order_book.0).unwrap();
+        assert_eq!(orders[0].status, OrderStatus::Cancelled);
+        assert_eq!(orders[1].status, OrderStatus::Open);
+        assert_eq!(order_book.add_order().id, 3);
+    }
+
+    #[test]
+    fn test_update_order_with_status() {
+        let order_book = TestOrderBook::new("status");
+        let order = order_book.add_order();
+
+        let pending = update_order_with_status(&order_book.0, order.id, OrderStatus::Open, |order| {
+            order.status = OrderStatus::Pending;
+            order.signature = Some(Signature::default().to_string());
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(pending.status, OrderStatus::Pending);
+
+        // The order is no longer open, so it is not sent a second time
+        let update = update_order_with_status(&order_book.0, order.id, OrderStatus::Open, |order| {
+            order.status = OrderStatus::Pending;
+        });
+        assert!(update.unwrap().is_none());
+
+        let filled = update_order_with_status(&order_book.0, order.id, OrderStatus::Pending, |order| {
+            order.status = OrderStatus::Filled;
+        });
+        assert_eq!(filled.unwrap().unwrap().status, OrderStatus::Filled);
+        assert_eq!(read_order_book(&order_book.0).unwrap()[0].status, OrderStatus::Filled);
+    }
+
+    #[test]
+    fn test_cancelled_order_is_not_overwritten() {
+        let order_book = TestOrderBook::new("cancelled");
+        let order = order_book.add_order();
+
+        cancel_order(&order_book.0, order.id).unwrap();
+
+        // Expiring or executing an order read as open before the cancellation leaves it cancelled
+        let update = update_order_with_status(&order_book.0, order.id, OrderStatus::Open, |order| {
+            order.status = OrderStatus::Expired;
+        });
+        assert!(update.unwrap().is_none());
+
+        reopen_order(&order_book.0, order.id, OrderStatus::Pending, "Transaction dropped".to_string()).unwrap();
+
+        let orders = read_order_book(&order_book.0).unwrap();
+        assert_eq!(orders[0].status, OrderStatus::Cancelled);
+        assert_eq!(orders[0].error, None);
+    }
+
+    #[test]
+    fn test_reopen_order() {
+        let order_book = TestOrderBook::new("reopen");
+        let order = order_book.add_order();
+
+        update_order_with_status(&order_book.0, order.id, OrderStatus::Open, |order| {
+            order.status = OrderStatus::Pending;
+            order.signature = Some(Signature::default().to_string());
+        })
+        .unwrap();
+        reopen_order(&order_book.0, order.id, OrderStatus::Pending, "Transaction dropped".to_string()).unwrap();
+
+        let orders = read_order_book(&order_book.0).unwrap();
+        assert_eq!(orders[0].status, OrderStatus::Open);
+        assert_eq!(orders[0].signature, None);
+        assert_eq!(orders[0].error.as_deref(), Some("Transaction dropped"));
+    }
+
+    #[test]
+    fn test_concurrent_orders_are_all_kept() {
+        let order_book = TestOrderBook::new("concurrent");
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        order_book.add_order();
+                    }
+                });
+            }
+        });
+
+        let mut ids = read_order_book(&order_book.0).unwrap().iter().map(|order| order.id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (1..=40).collect::<Vec<_>>());
+    }
+}
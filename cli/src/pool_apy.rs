@@ -0,0 +1,264 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anchor_client::{
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::pubkey::Pubkey,
+};
+use anyhow::Result;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    lp::{self, WITHDRAW_FEE_DIVISOR},
+    lp_position::{self, LpEvent, LpEventKind, SECONDS_PER_YEAR},
+};
+
+/// A snapshot of the pool, one JSON line in the snapshot store. Snapshots bootstrapped from the transaction history
+/// only know the LP share price observed in a deposit or withdrawal
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PoolSnapshot {
+    pub timestamp: i64,
+    pub slot: u64,
+    pub epoch: u64,
+    /// Lamports per LP token
+    pub lp_share_price: f64,
+    pub pool_value_lamports: Option<u64>,
+    pub total_lp_tokens: Option<u64>,
+    pub total_accrued_fees: Option<u64>,
+    pub total_stake_rewards_withdrawn: Option<u64>,
+    pub last_stake_rewards_withdrawn_epoch: Option<u32>,
+    #[serde(default)]
+    pub bootstrapped: bool,
+}
+
+/// LP return over a trailing window of epochs
+pub struct TrailingReturn {
+    pub epochs: u64,
+    /// Snapshots the return was measured between
+    pub from: PoolSnapshot,
+    pub to: PoolSnapshot,
+    pub return_pct: f64,
+    /// The return compounded over a year, using the time between the snapshots
+    pub apy_pct: f64,
+}
+
+/// Function to take a snapshot of the current pool state
+pub async fn take_pool_snapshot(rpc: &RpcClient, pool: &Pool) -> Result<PoolSnapshot> {
+    let slot = rpc.get_slot().await?;
+    let epoch = rpc.get_epoch_info().await?.epoch;
+    let timestamp = rpc.get_block_time(slot).await.unwrap_or(chrono::Utc::now().timestamp());
+
+    let pool_value_lamports = lp::get_pool_value_lamports(pool)?;
+
+    if pool.total_lp_tokens == 0 {
+        return Err(anyhow::anyhow!("The pool has no LP tokens, there is no share price to record"));
+    }
+
+    Ok(PoolSnapshot {
+        timestamp,
+        slot,
+        epoch,
+        lp_share_price: pool_value_lamports as f64 / pool.total_lp_tokens as f64,
+        pool_value_lamports: Some(pool_value_lamports),
+        total_lp_tokens: Some(pool.total_lp_tokens),
+        total_accrued_fees: Some(pool.total_accrued_fees),
+        total_stake_rewards_withdrawn: Some(pool.total_stake_rewards_withdrawn),
+        last_stake_rewards_withdrawn_epoch: Some(pool.last_stake_rewards_withdrawn_epoch),
+        bootstrapped: false,
+    })
+}
+
+/// Function to reconstruct snapshots from the deposits and withdrawals in the transaction history of the pool, each
+/// of which reveals the LP share price at that time. Withdrawals are grossed up with the current withdraw fee. The
+/// transactions of the LP mint are scanned rather than those of the pool, as unstakes do not touch the LP mint, so
+/// `max_transactions` only counts deposits and withdrawals
+pub async fn bootstrap_pool_snapshots(
+    rpc: &RpcClient,
+    program_id: &Pubkey,
+    pool_id: &Pubkey,
+    pool: &Pool,
+    max_transactions: usize,
+) -> Result<Vec<PoolSnapshot>> {
+    let epoch_schedule = rpc.get_epoch_schedule().await?;
+    let events = lp_position::get_lp_events(rpc, program_id, pool_id, &pool.lp_mint, Some(max_transactions)).await?;
+
+    let snapshots = events
+        .into_iter()
+        .filter(|event| event.lp_tokens > 0 && event.lamports > 0)
+        .filter_map(|event| {
+            Some(PoolSnapshot {
+                timestamp: event.block_time?,
+                slot: event.slot,
+                epoch: epoch_schedule.get_epoch(event.slot),
+                lp_share_price: get_lp_share_price(&event, pool),
+                pool_value_lamports: None,
+                total_lp_tokens: None,
+                total_accrued_fees: None,
+                total_stake_rewards_withdrawn: None,
+                last_stake_rewards_withdrawn_epoch: None,
+                bootstrapped: true,
+            })
+        })
+        .collect();
+
+    Ok(snapshots)
+}
+
+/// Function to get the LP share price revealed by a deposit or withdrawal. The SOL withdrawn is what is left after
+/// the withdraw fee, so it is grossed up with the current withdraw fee of the pool
+fn get_lp_share_price(event: &LpEvent, pool: &Pool) -> f64 {
+    let lamports = match event.kind {
+        LpEventKind::Deposit | LpEventKind::WithdrawStakeAccount => event.lamports as f64,
+        LpEventKind::WithdrawSol => {
+            event.lamports as f64 * WITHDRAW_FEE_DIVISOR as f64
+                / WITHDRAW_FEE_DIVISOR.saturating_sub(pool.withdraw_sol_fee as u64).max(1) as f64
+        }
+    };
+
+    lamports / event.lp_tokens as f64
+}
+
+/// Function to read the snapshot store, oldest first. A missing store has no snapshots
+pub fn read_pool_snapshots(path: &Path) -> Result<Vec<PoolSnapshot>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut snapshots = fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<PoolSnapshot>)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|err| anyhow::anyhow!("Invalid snapshot in {}: {}", path.display(), err))?;
+
+    snapshots.sort_by_key(|snapshot| snapshot.slot);
+
+    Ok(snapshots)
+}
+
+/// Function to add snapshots to the store, skipping the slots it already has
+pub fn append_pool_snapshots(path: &Path, snapshots: &[PoolSnapshot]) -> Result<usize> {
+    let existing_slots = read_pool_snapshots(path)?
+        .into_iter()
+        .map(|snapshot| snapshot.slot)
+        .collect::<std::collections::HashSet<_>>();
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut appended = 0;
+
+    for snapshot in snapshots.iter().filter(|snapshot| !existing_slots.contains(&snapshot.slot)) {
+        writeln!(file, "{}", serde_json::to_string(snapshot)?)?;
+        appended += 1;
+    }
+
+    Ok(appended)
+}
+
+/// Function to get the LP return over the trailing `epochs` epochs, between the latest snapshot and the latest
+/// snapshot at least `epochs` epochs older. None if the store does not go back that far
+pub fn get_trailing_return(snapshots: &[PoolSnapshot], epochs: u64) -> Option<TrailingReturn> {
+    let to = snapshots.last()?;
+    let from = snapshots
+        .iter()
+        .rev()
+        .find(|snapshot| snapshot.epoch + epochs <= to.epoch)?;
+
+    let elapsed_seconds = (to.timestamp - from.timestamp) as f64;
+
+    if from.lp_share_price <= 0.0 || elapsed_seconds <= 0.0 {
+        return None;
+    }
+
+    let growth = to.lp_share_price / from.lp_share_price;
+
+    Some(TrailingReturn {
+        epochs,
+        from: from.clone(),
+        to: to.clone(),
+        return_pct: (growth - 1.0) * 100.0,
+        apy_pct: (growth.powf(SECONDS_PER_YEAR / elapsed_seconds) - 1.0) * 100.0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::{native_token::LAMPORTS_PER_SOL, signature::Signature};
+
+    use super::*;
+    use crate::test_utils::get_test_pool;
+
+    const SECONDS_PER_EPOCH: i64 = 2 * 24 * 60 * 60;
+
+    fn get_snapshot(epoch: u64, lp_share_price: f64) -> PoolSnapshot {
+        PoolSnapshot {
+            timestamp: epoch as i64 * SECONDS_PER_EPOCH,
+            slot: epoch * 432_000,
+            epoch,
+            lp_share_price,
+            pool_value_lamports: None,
+            total_lp_tokens: None,
+            total_accrued_fees: None,
+            total_stake_rewards_withdrawn: None,
+            last_stake_rewards_withdrawn_epoch: None,
+            bootstrapped: true,
+        }
+    }
+
+    fn get_event(kind: LpEventKind, lp_tokens: u64, lamports: u64) -> LpEvent {
+        LpEvent {
+            signature: Signature::default(),
+            slot: 0,
+            block_time: Some(0),
+            kind,
+            lp_tokens,
+            lamports,
+        }
+    }
+
+    #[test]
+    fn test_get_trailing_return() {
+        let snapshots = [
+            get_snapshot(100, 1.0),
+            get_snapshot(101, 1.001),
+            get_snapshot(107, 1.01),
+            get_snapshot(108, 1.02),
+        ];
+
+        let trailing_return = get_trailing_return(&snapshots, 1).unwrap();
+        assert_eq!(trailing_return.from.epoch, 107);
+        assert!((trailing_return.return_pct - (1.02 / 1.01 - 1.0) * 100.0).abs() < 1e-9);
+
+        // The latest snapshot at least 7 epochs older
+        let trailing_return = get_trailing_return(&snapshots, 7).unwrap();
+        assert_eq!(trailing_return.from.epoch, 101);
+
+        // 2% over 8 epochs of 2 days, compounded over a year
+        let trailing_return = get_trailing_return(&snapshots, 8).unwrap();
+        assert_eq!(trailing_return.from.epoch, 100);
+        let expected_apy_pct = (1.02f64.powf(SECONDS_PER_YEAR / (8 * SECONDS_PER_EPOCH) as f64) - 1.0) * 100.0;
+        assert!((trailing_return.apy_pct - expected_apy_pct).abs() < 1e-9);
+
+        assert!(get_trailing_return(&snapshots, 9).is_none());
+        assert!(get_trailing_return(&[], 1).is_none());
+    }
+
+    #[test]
+    fn test_get_lp_share_price() {
+        // withdraw_sol_fee of 10 is 0.1%
+        let pool = get_test_pool();
+
+        assert_eq!(get_lp_share_price(&get_event(LpEventKind::Deposit, 100, 110), &pool), 1.1);
+        assert_eq!(get_lp_share_price(&get_event(LpEventKind::WithdrawStakeAccount, 100, 110), &pool), 1.1);
+
+        let share_price = get_lp_share_price(&get_event(LpEventKind::WithdrawSol, LAMPORTS_PER_SOL, 999_000_000), &pool);
+        assert!((share_price - 1.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,208 @@
+use anchor_client::{
+    solana_client::{
+        nonblocking::rpc_client::RpcClient,
+        rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+        rpc_filter::{Memcmp, RpcFilterType},
+    },
+    solana_sdk::{native_token::LAMPORTS_PER_SOL, pubkey::Pubkey},
+};
+use anchor_lang::{AccountDeserialize, Discriminator};
+use anyhow::Result;
+use liquid_unstaker::liquid_unstaker::accounts::{Pool, StakeAccountInfo};
+use solana_account_decoder::UiAccountEncoding;
+use spl_stake_pool::state::StakePool;
+
+use crate::{
+    fee::{Fee, FEE_PCT_DIVISOR},
+    lp::WITHDRAW_FEE_DIVISOR,
+    quote_lst_unstake, quote_lst_unstake_wrapped,
+};
+
+/// Function to get all unstake pools deployed under the given liquid unstaker program, found by the Anchor
+/// discriminator of the Pool account
+pub async fn get_unstake_pools(rpc: &RpcClient, program_id: &Pubkey) -> Result<Vec<(Pubkey, Pool)>> {
+    let pools = rpc
+        .get_program_accounts_with_config(
+            program_id,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(0, Pool::DISCRIMINATOR))]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    ..RpcAccountInfoConfig::default()
+                },
+                ..RpcProgramAccountsConfig::default()
+            },
+        )
+        .await?
+        .into_iter()
+        .filter_map(|(pubkey, account)| {
+            let mut data = account.data.as_slice();
+
+            Pool::try_deserialize(&mut data).ok().map(|pool| (pubkey, pool))
+        })
+        .collect();
+
+    Ok(pools)
+}
+
+/// Function to find the unstake pool giving the most lamports for unstaking the given amount of LST tokens.
+/// Returns the pool ID, the pool and the quote, or None if no pool can take the unstake
+pub fn find_best_pool(
+    pools: &[(Pubkey, Pool)],
+    stake_pool_state: &StakePool,
+    pool_tokens: u64,
+    wrapped: bool,
+    new_stake_account_as_pda: bool,
+) -> Option<(Pubkey, Pool, i64)> {
+    pools
+        .iter()
+        .filter_map(|(pool_id, pool)| {
+            let quote = if wrapped {
+                quote_lst_unstake_wrapped(stake_pool_state, pool, pool_tokens, new_stake_account_as_pda)
+                    .map(|(wsol_out, lamports_out)| wsol_out + lamports_out)
+            } else {
+                quote_lst_unstake(stake_pool_state, pool, pool_tokens)
+            };
+
+            quote.ok().map(|quote| (*pool_id, *pool, quote))
+        })
+        .max_by_key(|(_, _, quote)| *quote)
+}
+
+/// Function to print the pool state in a readable form, together with derived metrics and consistency checks
+/// against the on-chain SOL vault balance and LP mint supply
+pub async fn print_pool_info(rpc: &RpcClient, pool_id: &Pubkey, pool: &Pool) -> Result<()> {
+    let fee_pct = |fee: u64| fee as f64 * 100.0 / FEE_PCT_DIVISOR as f64;
+    let withdraw_fee_pct = |fee: u64| fee as f64 * 100.0 / WITHDRAW_FEE_DIVISOR as f64;
+    let sol = |lamports: u64| format!("{:.9} SOL", lamports as f64 / LAMPORTS_PER_SOL as f64);
+    let fill_pct = |lamports: u64, of: u64| {
+        if of == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.2}%", lamports as f64 * 100.0 / of as f64)
+        }
+    };
+
+    // The marginal fee is the fee rate charged on the next lamport unstaked at the current vault level
+    let marginal_fee = Fee::calculate_base_fee(pool, pool.sol_vault_lamports, 0)?;
+    let fee_tier = if pool.sol_vault_lamports >= pool.min_sol_for_min_fee {
+        "minimum fee, vault above min_sol_for_min_fee"
+    } else {
+        "linear fee, vault below min_sol_for_min_fee"
+    };
+
+    let lp_backing_lamports = pool.sol_vault_lamports + pool.total_deactivating_stake;
+
+    println!("Pool:                        {}", pool_id);
+    println!("Authority:                   {}", pool.authority);
+    println!("Manager fee account:         {}", pool.manager_fee_account);
+    println!("SOL vault:                   {}", pool.sol_vault);
+    println!("LP mint:                     {}", pool.lp_mint);
+    println!();
+    println!("Fees");
+    println!("  Min fee:                   {:.3}%", fee_pct(pool.fee_min.into()));
+    println!("  Max fee:                   {:.3}%", fee_pct(pool.fee_max.into()));
+    println!("  Current marginal fee:      {:.3}% ({})", fee_pct(marginal_fee), fee_tier);
+    println!("  Manager share of fees:     {}%", pool.manager_fee_pct);
+    println!("  Withdraw SOL fee:          {:.2}%", withdraw_fee_pct(pool.withdraw_sol_fee.into()));
+    println!("  Withdraw stake fee:        {:.2}%", withdraw_fee_pct(pool.withdraw_stake_account_fee.into()));
+    println!();
+    println!("Liquidity");
+    println!("  SOL vault:                 {}", sol(pool.sol_vault_lamports));
+    println!(
+        "  Min SOL for min fee:       {} ({} filled)",
+        sol(pool.min_sol_for_min_fee),
+        fill_pct(pool.sol_vault_lamports, pool.min_sol_for_min_fee)
+    );
+    println!(
+        "  SOL vault cap:             {} ({} filled)",
+        sol(pool.sol_vault_lamports_cap),
+        fill_pct(pool.sol_vault_lamports, pool.sol_vault_lamports_cap)
+    );
+    println!("  Deactivating stake:        {}", sol(pool.total_deactivating_stake));
+    println!();
+    println!("LP token");
+    println!("  Supply:                    {}", pool.total_lp_tokens);
+    if pool.total_lp_tokens > 0 {
+        println!(
+            "  Value:                     {:.9} lamports per LP token (vault + deactivating stake)",
+            lp_backing_lamports as f64 / pool.total_lp_tokens as f64
+        );
+    }
+    println!();
+    println!("Totals");
+    println!("  Accrued fees:              {}", sol(pool.total_accrued_fees));
+    println!(
+        "  Stake rewards withdrawn:   {} (last in epoch {})",
+        sol(pool.total_stake_rewards_withdrawn),
+        pool.last_stake_rewards_withdrawn_epoch
+    );
+    println!();
+    println!("Flash loans");
+    println!("  Enabled:                   {}", pool.flash_loans_enabled);
+    println!("  Fee:                       {:.3}%", fee_pct(pool.flash_loan_fee.into()));
+    if pool.flash_loan_borrowed_amount > 0 {
+        println!("  Active loan:               {}", sol(pool.flash_loan_borrowed_amount));
+    } else {
+        println!("  Active loan:               none");
+    }
+    println!();
+
+    // Consistency checks against the on-chain accounts
+    println!("Checks");
+
+    let sol_vault_balance = rpc.get_balance(&pool.sol_vault).await?;
+    let sol_vault_ok = sol_vault_balance >= pool.sol_vault_lamports;
+    println!(
+        "  SOL vault balance:         {} on-chain, {} accounted, {}",
+        sol(sol_vault_balance),
+        sol(pool.sol_vault_lamports),
+        if sol_vault_ok { "OK" } else { "MISMATCH, balance below the accounted lamports" }
+    );
+
+    let lp_mint_supply = rpc.get_token_supply(&pool.lp_mint).await?.amount.parse::<u64>()?;
+    println!(
+        "  LP mint supply:            {} on-chain, {} accounted, {}",
+        lp_mint_supply,
+        pool.total_lp_tokens,
+        if lp_mint_supply == pool.total_lp_tokens { "OK" } else { "MISMATCH" }
+    );
+
+    Ok(())
+}
+
+/// Function to get the stake accounts held by the unstake pool, as the StakeAccountInfo address and account
+pub async fn get_pool_stake_accounts(
+    rpc: &RpcClient,
+    program_id: &Pubkey,
+    pool_id: &Pubkey,
+) -> Result<Vec<(Pubkey, StakeAccountInfo)>> {
+    // The pool is stored right after the discriminator and the stake account
+    let pool_offset = StakeAccountInfo::DISCRIMINATOR.len() + 32;
+
+    let stake_accounts = rpc
+        .get_program_accounts_with_config(
+            program_id,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![
+                    RpcFilterType::Memcmp(Memcmp::new_base58_encoded(0, StakeAccountInfo::DISCRIMINATOR)),
+                    RpcFilterType::Memcmp(Memcmp::new_base58_encoded(pool_offset, &pool_id.to_bytes())),
+                ]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    ..RpcAccountInfoConfig::default()
+                },
+                ..RpcProgramAccountsConfig::default()
+            },
+        )
+        .await?
+        .into_iter()
+        .filter_map(|(pubkey, account)| {
+            let mut data = account.data.as_slice();
+
+            StakeAccountInfo::try_deserialize(&mut data).ok().map(|info| (pubkey, info))
+        })
+        .collect();
+
+    Ok(stake_accounts)
+}
@@ -0,0 +1,291 @@
+use std::{cmp::Reverse, collections::HashMap};
+
+use anchor_client::{
+    solana_client::{
+        nonblocking::rpc_client::RpcClient,
+        rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+        rpc_filter::{Memcmp, RpcFilterType},
+    },
+    solana_sdk::pubkey::Pubkey,
+};
+use anchor_spl::{
+    token::spl_token::{self, solana_program::program_pack::Pack},
+    token_2022::spl_token_2022,
+};
+use anyhow::Result;
+use itertools::Itertools;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use solana_account_decoder::UiAccountEncoding;
+use spl_stake_pool::state::StakePool;
+
+use crate::{
+    get_stake_pools, quote_lst_unstake, quote_lst_unstake_breakdown,
+    token::unpack_token_account,
+    SUPPORTED_STAKE_POOL_PROGRAMS,
+};
+
+/// Above this number of holdings we no longer try every unstake order and fall back to largest first
+const MAX_HOLDINGS_FOR_EXHAUSTIVE_ORDERING: usize = 7;
+
+/// An LST token account of a wallet together with the stake pool backing the LST
+pub struct LstHolding {
+    pub token_account: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub stake_pool_program_id: Pubkey,
+    pub stake_pool_state: StakePool,
+    /// Lamports the tokens are worth at the stake pool exchange rate
+    pub sol_value: u64,
+    /// Lamports received when instantly unstaking the whole balance through the unstake pool, if possible
+    pub unstake_value: Option<i64>,
+}
+
+impl LstHolding {
+    /// Discount of the instant unstake compared to the stake pool value, in percent
+    pub fn discount_pct(&self) -> Option<f64> {
+        match self.unstake_value {
+            Some(unstake_value) if self.sol_value > 0 => {
+                Some((1.0 - unstake_value as f64 / self.sol_value as f64) * 100.0)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A single unstake of a planned unstake-all run, in execution order
+pub struct UnstakePlanStep {
+    /// Index into the holdings the plan was made for
+    pub holding: usize,
+    /// LST tokens unstaked, holdings larger than a single unstake can take are unstaked in several steps
+    pub amount: u64,
+    /// SOL vault lamports of the unstake pool before this unstake
+    pub sol_vault_lamports: u64,
+    /// Expected lamports received for this unstake
+    pub lamports_out: i64,
+}
+
+/// Function to get all token accounts of the owner holding an LST supported by the unstake pool
+pub async fn get_lst_holdings(
+    rpc: &RpcClient,
+    owner: &Pubkey,
+    unstake_pool_info: &Pool,
+) -> Result<Vec<LstHolding>> {
+    // Map every supported LST mint to its stake pool
+    let mut stake_pools = HashMap::new();
+
+    for program_id in SUPPORTED_STAKE_POOL_PROGRAMS {
+        for (_stake_pool_address, stake_pool_state) in get_stake_pools(rpc, &program_id).await? {
+            stake_pools
+                .entry(stake_pool_state.pool_mint)
+                .or_insert((program_id, stake_pool_state));
+        }
+    }
+
+    let mut token_accounts = vec![];
+
+    for token_program_id in [spl_token::id(), spl_token_2022::id()] {
+        let mut filters = vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(32, &owner.to_bytes()))];
+
+        // Token-2022 accounts with extensions are larger than the base account
+        if token_program_id == spl_token::id() {
+            filters.push(RpcFilterType::DataSize(spl_token::state::Account::LEN as u64));
+        }
+
+        token_accounts.extend(
+            rpc.get_program_accounts_with_config(
+                &token_program_id,
+                RpcProgramAccountsConfig {
+                    filters: Some(filters),
+                    account_config: RpcAccountInfoConfig {
+                        encoding: Some(UiAccountEncoding::Base64),
+                        ..RpcAccountInfoConfig::default()
+                    },
+                    ..RpcProgramAccountsConfig::default()
+                },
+            )
+            .await?,
+        );
+    }
+
+    let mut holdings = vec![];
+
+    for (token_account, account) in token_accounts {
+        // Skip anything that is not a token account, e.g. Token-2022 mints matching the owner filter
+        let Ok(token_account_state) = unpack_token_account(&account.data) else {
+            continue;
+        };
+
+        let Some((stake_pool_program_id, stake_pool_state)) =
+            stake_pools.get(&token_account_state.mint)
+        else {
+            continue;
+        };
+
+        let sol_value = stake_pool_state
+            .calc_lamports_withdraw_amount(token_account_state.amount)
+            .unwrap_or(0);
+
+        let unstake_value = if token_account_state.amount > 0 {
+            quote_lst_unstake(stake_pool_state, unstake_pool_info, token_account_state.amount).ok()
+        } else {
+            None
+        };
+
+        holdings.push(LstHolding {
+            token_account,
+            mint: token_account_state.mint,
+            amount: token_account_state.amount,
+            stake_pool_program_id: *stake_pool_program_id,
+            stake_pool_state: stake_pool_state.clone(),
+            sol_value,
+            unstake_value,
+        });
+    }
+
+    // Largest positions first
+    holdings.sort_by_key(|holding| Reverse(holding.sol_value));
+
+    Ok(holdings)
+}
+
+/// Function to plan unstaking all the given holdings, in the order that results in the most lamports received.
+///
+/// Every unstake drains the SOL vault, which moves the following unstakes along the fee curve, so the order
+/// matters. `max_pool_tokens_per_unstake` holds for every holding the most tokens a single unstake can take, as it
+/// is limited by the validator stake accounts of the stake pool, larger holdings are split into several unstakes.
+/// Holdings that cannot be unstaked (e.g. not enough liquidity left) are left out of the plan, or the part of them
+/// that cannot be.
+pub fn plan_unstake_all(
+    holdings: &[LstHolding],
+    max_pool_tokens_per_unstake: &[u64],
+    unstake_pool_info: &Pool,
+) -> Result<Vec<UnstakePlanStep>> {
+    let simulate_order = |order: &[usize]| -> (Vec<UnstakePlanStep>, i64) {
+        let mut pool = *unstake_pool_info;
+        let mut steps = vec![];
+        let mut total_lamports_out = 0;
+
+        for &holding in order {
+            let mut remaining = holdings[holding].amount;
+
+            while remaining > 0 && max_pool_tokens_per_unstake[holding] > 0 {
+                let amount = remaining.min(max_pool_tokens_per_unstake[holding]);
+
+                let Ok(quote) = quote_lst_unstake_breakdown(&holdings[holding].stake_pool_state, &pool, amount) else {
+                    break;
+                };
+
+                steps.push(UnstakePlanStep {
+                    holding,
+                    amount,
+                    sol_vault_lamports: pool.sol_vault_lamports,
+                    lamports_out: quote.lamports_out,
+                });
+
+                // Both the lamports paid out to the user and the manager fee leave the vault
+                pool.sol_vault_lamports = pool
+                    .sol_vault_lamports
+                    .saturating_sub(quote.lamports_out.max(0) as u64 + quote.fee.manager_fee);
+                total_lamports_out += quote.lamports_out;
+                remaining -= amount;
+            }
+        }
+
+        (steps, total_lamports_out)
+    };
+
+    let candidates = holdings
+        .iter()
+        .enumerate()
+        .filter(|(_, holding)| holding.amount > 0)
+        .map(|(i, _)| i)
+        .collect_vec();
+
+    let (steps, _) = if candidates.len() <= MAX_HOLDINGS_FOR_EXHAUSTIVE_ORDERING {
+        candidates
+            .iter()
+            .copied()
+            .permutations(candidates.len())
+            .map(|order| simulate_order(&order))
+            .max_by_key(|(steps, total_lamports_out)| (steps.len(), *total_lamports_out))
+            .unwrap_or_default()
+    } else {
+        let order = candidates
+            .into_iter()
+            .sorted_by_key(|i| Reverse(holdings[*i].sol_value))
+            .collect_vec();
+
+        simulate_order(&order)
+    };
+
+    if steps.is_empty() {
+        return Err(anyhow::anyhow!("None of the holdings can be unstaked through the unstake pool"));
+    }
+
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+
+    use super::*;
+    use crate::test_utils::{get_test_pool, get_test_stake_pool};
+
+    fn get_holding(amount: u64) -> LstHolding {
+        LstHolding {
+            token_account: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            amount,
+            stake_pool_program_id: Pubkey::new_unique(),
+            stake_pool_state: get_test_stake_pool(),
+            sol_value: amount,
+            unstake_value: None,
+        }
+    }
+
+    #[test]
+    fn test_plan_unstake_all_splits_large_holdings() {
+        let pool = get_test_pool();
+        let holdings = [get_holding(250 * LAMPORTS_PER_SOL)];
+
+        let plan = plan_unstake_all(&holdings, &[100 * LAMPORTS_PER_SOL], &pool).unwrap();
+
+        assert_eq!(
+            plan.iter().map(|step| step.amount).collect_vec(),
+            vec![100 * LAMPORTS_PER_SOL, 100 * LAMPORTS_PER_SOL, 50 * LAMPORTS_PER_SOL]
+        );
+        assert_eq!(plan[0].sol_vault_lamports, pool.sol_vault_lamports);
+
+        for (step, next_step) in plan.iter().tuple_windows() {
+            let quote = quote_lst_unstake_breakdown(
+                &holdings[0].stake_pool_state,
+                &Pool {
+                    sol_vault_lamports: step.sol_vault_lamports,
+                    ..pool
+                },
+                step.amount,
+            )
+            .unwrap();
+
+            // The manager fee leaves the vault together with the lamports paid out
+            assert!(quote.fee.manager_fee > 0);
+            assert_eq!(
+                next_step.sol_vault_lamports,
+                step.sol_vault_lamports - step.lamports_out as u64 - quote.fee.manager_fee
+            );
+        }
+    }
+
+    #[test]
+    fn test_plan_unstake_all_skips_holdings_without_stake_to_withdraw() {
+        let pool = get_test_pool();
+        let holdings = [get_holding(10 * LAMPORTS_PER_SOL), get_holding(20 * LAMPORTS_PER_SOL)];
+
+        let plan = plan_unstake_all(&holdings, &[0, 100 * LAMPORTS_PER_SOL], &pool).unwrap();
+
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].holding, 1);
+        assert!(plan_unstake_all(&holdings[..1], &[0], &pool).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use anchor_client::{
+    solana_client::nonblocking::rpc_client::RpcClient,
+    solana_sdk::{self, epoch_info::EpochInfo, instruction::Instruction, pubkey::Pubkey, signature::Keypair, signer::Signer},
+};
+use anyhow::Result;
+use solana_stake_interface::{self as stake, state::StakeStateV2};
+use spl_stake_pool::state::{StakePool, ValidatorList};
+
+/// Approximate slot time, to turn the slots left in the epoch into a duration
+const SLOT_DURATION_MS: u64 = 400;
+
+/// Ways to turn LST tokens into SOL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RouteKind {
+    /// Liquid unstake through the unstake pool, SOL right away
+    Instant,
+    /// WithdrawSol from the stake pool reserve, SOL right away
+    WithdrawSol,
+    /// WithdrawStake from the stake pool into new stake accounts that are deactivated, SOL once the stake has
+    /// cooled down at the end of the epoch
+    Delayed,
+}
+
+impl RouteKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RouteKind::Instant => "instant",
+            RouteKind::WithdrawSol => "withdraw-sol",
+            RouteKind::Delayed => "delayed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RouteKind> {
+        [RouteKind::Instant, RouteKind::WithdrawSol, RouteKind::Delayed]
+            .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+/// Quote of one route, `lamports_out` is None when the route is not available
+pub struct RouteQuote {
+    pub kind: RouteKind,
+    pub lamports_out: Option<i64>,
+    pub unavailable_reason: Option<String>,
+    /// Epoch from which the SOL can be used
+    pub liquid_epoch: u64,
+    /// Approximate time until the SOL can be used
+    pub seconds_to_liquidity: u64,
+}
+
+impl RouteQuote {
+    /// Function to build the quote of a route from the quoted lamports or the reason the route is not available
+    pub fn new(kind: RouteKind, lamports_out: Result<i64>, epoch_info: &EpochInfo) -> RouteQuote {
+        let (liquid_epoch, seconds_to_liquidity) = match kind {
+            RouteKind::Instant | RouteKind::WithdrawSol => (epoch_info.epoch, 0),
+            // The stake is deactivated this epoch and can be withdrawn from the next one, as long as the cluster
+            // wide cooldown rate limit does not spread the deactivation over more epochs
+            RouteKind::Delayed => (
+                epoch_info.epoch + 1,
+                (epoch_info.slots_in_epoch - epoch_info.slot_index) * SLOT_DURATION_MS / 1000,
+            ),
+        };
+
+        let (lamports_out, unavailable_reason) = match lamports_out {
+            Ok(lamports_out) => (Some(lamports_out), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+
+        RouteQuote {
+            kind,
+            lamports_out,
+            unavailable_reason,
+            liquid_epoch,
+            seconds_to_liquidity,
+        }
+    }
+}
+
+/// Function to check that the stake pool was updated this epoch, withdrawals from a stake pool that is out of date
+/// fail
+pub fn check_stake_pool_up_to_date(stake_pool_state: &StakePool, epoch: u64) -> Result<()> {
+    if stake_pool_state.last_update_epoch < epoch {
+        return Err(anyhow::anyhow!(
+            "Stake pool was last updated in epoch {}, it needs an update for epoch {} first",
+            stake_pool_state.last_update_epoch,
+            epoch
+        ));
+    }
+
+    Ok(())
+}
+
+/// Function to get the lamports received for withdrawing the given LST tokens as SOL from the stake pool reserve.
+/// `reserve_lamports` is the balance of the reserve stake account and `reserve_rent_exempt_reserve` the part of it
+/// that has to stay behind
+pub fn quote_withdraw_sol_route(
+    stake_pool_state: &StakePool,
+    reserve_lamports: u64,
+    reserve_rent_exempt_reserve: u64,
+    pool_tokens: u64,
+) -> Result<i64> {
+    if stake_pool_state.sol_withdraw_authority.is_some() {
+        return Err(anyhow::anyhow!("The stake pool only allows SOL withdrawals by its SOL withdraw authority"));
+    }
+
+    let fee_tokens = stake_pool_state
+        .calc_pool_tokens_sol_withdrawal_fee(pool_tokens)
+        .ok_or(anyhow::anyhow!("Math overflow"))?;
+    let lamports = stake_pool_state
+        .calc_lamports_withdraw_amount(pool_tokens - fee_tokens)
+        .ok_or(anyhow::anyhow!("Math overflow"))?;
+
+    let available_lamports =
+        reserve_lamports.saturating_sub(spl_stake_pool::MINIMUM_RESERVE_LAMPORTS + reserve_rent_exempt_reserve);
+
+    if lamports > available_lamports {
+        return Err(anyhow::anyhow!(
+            "The stake pool reserve only has {} lamports available",
+            available_lamports
+        ));
+    }
+
+    Ok(lamports as i64)
+}
+
+/// Function to get the lamports of stake received for withdrawing the given LST tokens as stake from the validator
+/// stake accounts of the stake pool. The rent the new stake accounts are funded with is returned when they are
+/// withdrawn, so it is not part of the quote
+pub fn quote_delayed_route(
+    stake_pool_state: &StakePool,
+    stake_pool_validator_list: &ValidatorList,
+    pool_tokens: u64,
+) -> Result<i64> {
+    let max_pool_tokens = crate::max_unstake::get_max_pool_tokens_for_stake_accounts(
+        stake_pool_state,
+        stake_pool_validator_list,
+        crate::max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE,
+    );
+
+    if pool_tokens > max_pool_tokens {
+        return Err(anyhow::anyhow!(
+            "The validator stake accounts of the stake pool only cover {} tokens",
+            max_pool_tokens
+        ));
+    }
+
+    let fee_tokens = stake_pool_state
+        .calc_pool_tokens_stake_withdrawal_fee(pool_tokens)
+        .ok_or(anyhow::anyhow!("Math overflow"))?;
+
+    stake_pool_state
+        .calc_lamports_withdraw_amount(pool_tokens - fee_tokens)
+        .map(|lamports| lamports as i64)
+        .ok_or(anyhow::anyhow!("Math overflow"))
+}
+
+/// Function to get the instructions withdrawing the given LST tokens from the stake pool as stake into new stake
+/// accounts owned by `authority`, and deactivating them. `withdrawals` are the validator stake accounts to split from
+/// with the LST tokens for each, `new_stake_accounts` the keypairs of the stake accounts to create, one for each
+#[allow(clippy::too_many_arguments)]
+pub fn get_delayed_route_instructions(
+    spl_stake_pool_program_id: &Pubkey,
+    spl_stake_pool_address: &Pubkey,
+    spl_stake_pool_state: &StakePool,
+    payer: &Pubkey,
+    authority: &Pubkey,
+    user_lst_account: &Pubkey,
+    withdrawals: &[(Pubkey, u64)],
+    new_stake_accounts: &[Keypair],
+) -> Vec<Instruction> {
+    let stake_pool_withdraw_authority = Pubkey::find_program_address(
+        &[&spl_stake_pool_address.to_bytes(), b"withdraw"],
+        spl_stake_pool_program_id,
+    )
+    .0;
+    let stake_account_rent = solana_sdk::rent::Rent::default().minimum_balance(StakeStateV2::size_of());
+
+    withdrawals
+        .iter()
+        .zip(new_stake_accounts)
+        .flat_map(|((stake_to_split, pool_tokens), new_stake_account)| {
+            [
+                solana_sdk::system_instruction::create_account(
+                    payer,
+                    &new_stake_account.pubkey(),
+                    stake_account_rent,
+                    StakeStateV2::size_of() as u64,
+                    &stake::program::id(),
+                ),
+                spl_stake_pool::instruction::withdraw_stake(
+                    spl_stake_pool_program_id,
+                    spl_stake_pool_address,
+                    &spl_stake_pool_state.validator_list,
+                    &stake_pool_withdraw_authority,
+                    stake_to_split,
+                    &new_stake_account.pubkey(),
+                    authority,
+                    authority,
+                    user_lst_account,
+                    &spl_stake_pool_state.manager_fee_account,
+                    &spl_stake_pool_state.pool_mint,
+                    &spl_stake_pool_state.token_program_id,
+                    *pool_tokens,
+                ),
+                stake::instruction::deactivate_stake(&new_stake_account.pubkey(), authority),
+            ]
+        })
+        .collect()
+}
+
+/// Function to get the balance and the rent exempt reserve of the reserve stake account of the stake pool
+pub async fn get_reserve_stake_balance(rpc: &RpcClient, stake_pool_state: &StakePool) -> Result<(u64, u64)> {
+    let account = rpc.get_account(&stake_pool_state.reserve_stake).await?;
+
+    let rent_exempt_reserve = match bincode::deserialize::<StakeStateV2>(&account.data)? {
+        StakeStateV2::Initialized(meta) | StakeStateV2::Stake(meta, _, _) => meta.rent_exempt_reserve,
+        _ => return Err(anyhow::anyhow!("Invalid reserve stake account {}", stake_pool_state.reserve_stake)),
+    };
+
+    Ok((account.lamports, rent_exempt_reserve))
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+    use spl_stake_pool::state::Fee as StakePoolFee;
+
+    use super::*;
+    use crate::test_utils::{get_test_stake_pool, get_test_validator_list};
+
+    const RESERVE_RENT_EXEMPT_RESERVE: u64 = 2_282_880;
+
+    #[test]
+    fn test_route_quote_liquidity() {
+        let epoch_info = EpochInfo {
+            epoch: 500,
+            slot_index: 420_000,
+            slots_in_epoch: 432_000,
+            absolute_slot: 0,
+            block_height: 0,
+            transaction_count: None,
+        };
+
+        let instant = RouteQuote::new(RouteKind::Instant, Ok(1_000), &epoch_info);
+        assert_eq!((instant.lamports_out, instant.liquid_epoch, instant.seconds_to_liquidity), (Some(1_000), 500, 0));
+
+        // Liquid once the rest of the epoch has passed
+        let delayed = RouteQuote::new(RouteKind::Delayed, Ok(1_000), &epoch_info);
+        assert_eq!((delayed.liquid_epoch, delayed.seconds_to_liquidity), (501, 12_000 * 400 / 1_000));
+
+        let unavailable = RouteQuote::new(RouteKind::WithdrawSol, Err(anyhow::anyhow!("Reserve empty")), &epoch_info);
+        assert_eq!(unavailable.lamports_out, None);
+        assert_eq!(unavailable.unavailable_reason.as_deref(), Some("Reserve empty"));
+    }
+
+    #[test]
+    fn test_check_stake_pool_up_to_date() {
+        let stake_pool = StakePool {
+            last_update_epoch: 500,
+            ..get_test_stake_pool()
+        };
+
+        assert!(check_stake_pool_up_to_date(&stake_pool, 500).is_ok());
+        assert!(check_stake_pool_up_to_date(&stake_pool, 501).is_err());
+    }
+
+    #[test]
+    fn test_quote_withdraw_sol_route() {
+        // 0.1% SOL withdrawal fee
+        let stake_pool = StakePool {
+            sol_withdrawal_fee: StakePoolFee {
+                denominator: 1_000,
+                numerator: 1,
+            },
+            ..get_test_stake_pool()
+        };
+        let reserve_lamports = 10 * LAMPORTS_PER_SOL + spl_stake_pool::MINIMUM_RESERVE_LAMPORTS + RESERVE_RENT_EXEMPT_RESERVE;
+
+        assert_eq!(
+            quote_withdraw_sol_route(&stake_pool, reserve_lamports, RESERVE_RENT_EXEMPT_RESERVE, 10 * LAMPORTS_PER_SOL)
+                .unwrap(),
+            (10 * LAMPORTS_PER_SOL - LAMPORTS_PER_SOL / 100) as i64
+        );
+
+        // The reserve has to keep its minimum and its rent exempt reserve
+        assert!(quote_withdraw_sol_route(
+            &stake_pool,
+            reserve_lamports,
+            RESERVE_RENT_EXEMPT_RESERVE,
+            11 * LAMPORTS_PER_SOL
+        )
+        .is_err());
+
+        let stake_pool = StakePool {
+            sol_withdraw_authority: Some(Pubkey::new_unique()),
+            ..stake_pool
+        };
+        assert!(quote_withdraw_sol_route(&stake_pool, reserve_lamports, RESERVE_RENT_EXEMPT_RESERVE, LAMPORTS_PER_SOL)
+            .is_err());
+    }
+
+    #[test]
+    fn test_quote_delayed_route() {
+        // 0.5% stake withdrawal fee
+        let stake_pool = StakePool {
+            stake_withdrawal_fee: StakePoolFee {
+                denominator: 1_000,
+                numerator: 5,
+            },
+            ..get_test_stake_pool()
+        };
+        let validator_list = get_test_validator_list(&[4 * LAMPORTS_PER_SOL, 6 * LAMPORTS_PER_SOL]);
+
+        assert_eq!(
+            quote_delayed_route(&stake_pool, &validator_list, 10 * LAMPORTS_PER_SOL).unwrap(),
+            (10 * LAMPORTS_PER_SOL - LAMPORTS_PER_SOL / 20) as i64
+        );
+
+        // The fee is taken in LST tokens, so a bit more than the stake of the validator stake accounts is covered
+        let max_pool_tokens = crate::max_unstake::get_max_pool_tokens_for_stake_accounts(
+            &stake_pool,
+            &validator_list,
+            crate::max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE,
+        );
+        assert!(max_pool_tokens > 10 * LAMPORTS_PER_SOL);
+        assert!(quote_delayed_route(&stake_pool, &validator_list, max_pool_tokens).is_ok());
+        assert!(quote_delayed_route(&stake_pool, &validator_list, max_pool_tokens + 1).is_err());
+    }
+}
@@ -0,0 +1,666 @@
+use std::{cmp::Reverse, collections::HashMap, rc::Rc};
+
+use anchor_client::{
+    solana_client::{
+        nonblocking::rpc_client::RpcClient,
+        rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+        rpc_filter::{Memcmp, RpcFilterType},
+    },
+    solana_sdk::{
+        account::Account, clock::Clock, instruction::Instruction, packet::PACKET_DATA_SIZE, pubkey, pubkey::Pubkey,
+        signature::Keypair, sysvar, transaction::Transaction,
+    },
+    Program,
+};
+use anyhow::Result;
+use itertools::Itertools;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use solana_account_decoder::UiAccountEncoding;
+use solana_stake_interface::{
+    self as stake,
+    stake_history::{StakeHistory, StakeHistoryEntry},
+    state::{warmup_cooldown_rate, Meta, Stake, StakeStateV2},
+};
+
+use crate::fee::{Fee, FEE_PCT_DIVISOR};
+
+/// Offset of the staker authority in a stake account, after the state tag and the rent exempt reserve
+const STAKER_OFFSET: usize = 4 + 8;
+/// Offset of the withdrawer authority in a stake account, right after the staker
+const WITHDRAWER_OFFSET: usize = STAKER_OFFSET + 32;
+/// Feature gate that lowered the stake warmup and cooldown rate from 25% to 9% per epoch
+const REDUCE_STAKE_WARMUP_COOLDOWN_FEATURE: Pubkey = pubkey!("GwtDQBghCTBgmX2cpEGNPxTEBUTQRaDMGTr5qychdGMj");
+/// Limit of the epochs projected for a cooldown, the cooldown rate of the cluster could stall the projection
+const MAX_COOLDOWN_EPOCHS: u64 = 100;
+
+/// Activation status of a stake account
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StakeStatus {
+    /// Initialized but not delegated
+    Undelegated,
+    Activating,
+    Active,
+    Deactivating,
+    Inactive,
+}
+
+/// A stake account of the wallet
+pub struct WalletStakeAccount {
+    pub address: Pubkey,
+    pub lamports: u64,
+    pub meta: Meta,
+    /// None if the stake account is not delegated
+    pub stake: Option<Stake>,
+    pub status: StakeStatus,
+}
+
+impl WalletStakeAccount {
+    pub fn voter(&self) -> Option<Pubkey> {
+        self.stake.map(|stake| stake.delegation.voter_pubkey)
+    }
+}
+
+/// Function to get all stake accounts where the wallet is staker or withdrawer
+pub async fn get_wallet_stake_accounts(rpc: &RpcClient, wallet: &Pubkey) -> Result<Vec<WalletStakeAccount>> {
+    let epoch = rpc.get_epoch_info().await?.epoch;
+    let stake_history = get_stake_history(rpc).await?;
+    let new_rate_activation_epoch = get_new_rate_activation_epoch(rpc).await?;
+    let mut stake_accounts = HashMap::new();
+
+    for offset in [STAKER_OFFSET, WITHDRAWER_OFFSET] {
+        let accounts = rpc
+            .get_program_accounts_with_config(
+                &stake::program::id(),
+                RpcProgramAccountsConfig {
+                    filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_base58_encoded(offset, &wallet.to_bytes()))]),
+                    account_config: RpcAccountInfoConfig {
+                        encoding: Some(UiAccountEncoding::Base64),
+                        ..RpcAccountInfoConfig::default()
+                    },
+                    ..RpcProgramAccountsConfig::default()
+                },
+            )
+            .await?;
+
+        for (address, account) in accounts {
+            if let Some(stake_account) =
+                parse_stake_account(address, &account, &stake_history, epoch, new_rate_activation_epoch)
+            {
+                stake_accounts.insert(address, stake_account);
+            }
+        }
+    }
+
+    let mut stake_accounts = stake_accounts.into_values().collect::<Vec<_>>();
+    stake_accounts.sort_by_key(|stake_account| std::cmp::Reverse(stake_account.lamports));
+
+    Ok(stake_accounts)
+}
+
+/// Function to get a single stake account
+pub async fn get_stake_account(rpc: &RpcClient, address: &Pubkey) -> Result<WalletStakeAccount> {
+    let epoch = rpc.get_epoch_info().await?.epoch;
+    let stake_history = get_stake_history(rpc).await?;
+    let new_rate_activation_epoch = get_new_rate_activation_epoch(rpc).await?;
+    let account = rpc
+        .get_account(address)
+        .await
+        .map_err(|_| anyhow::anyhow!("Stake account {} not found", address))?;
+
+    if account.owner != stake::program::id() {
+        return Err(anyhow::anyhow!("Account {} is not a stake account", address));
+    }
+
+    parse_stake_account(*address, &account, &stake_history, epoch, new_rate_activation_epoch)
+        .ok_or(anyhow::anyhow!("Stake account {} is not initialized", address))
+}
+
+fn parse_stake_account(
+    address: Pubkey,
+    account: &Account,
+    stake_history: &StakeHistory,
+    epoch: u64,
+    new_rate_activation_epoch: Option<u64>,
+) -> Option<WalletStakeAccount> {
+    let (meta, stake) = match bincode::deserialize::<StakeStateV2>(&account.data).ok()? {
+        StakeStateV2::Initialized(meta) => (meta, None),
+        StakeStateV2::Stake(meta, stake, _) => (meta, Some(stake)),
+        _ => return None,
+    };
+
+    Some(WalletStakeAccount {
+        address,
+        lamports: account.lamports,
+        meta,
+        stake,
+        status: get_stake_status(stake.as_ref(), stake_history, epoch, new_rate_activation_epoch),
+    })
+}
+
+/// Function to get the activation status of a stake account from the stake history, the warmup and cooldown of a
+/// stake account can take several epochs when the cluster wide rate limit kicks in
+pub fn get_stake_status(
+    stake: Option<&Stake>,
+    stake_history: &StakeHistory,
+    epoch: u64,
+    new_rate_activation_epoch: Option<u64>,
+) -> StakeStatus {
+    let Some(stake) = stake else {
+        return StakeStatus::Undelegated;
+    };
+
+    get_activation_status(&stake.delegation.stake_activating_and_deactivating(
+        epoch,
+        stake_history,
+        new_rate_activation_epoch,
+    ))
+}
+
+fn get_activation_status(status: &StakeHistoryEntry) -> StakeStatus {
+    if status.deactivating > 0 {
+        StakeStatus::Deactivating
+    } else if status.activating > 0 {
+        StakeStatus::Activating
+    } else if status.effective > 0 {
+        StakeStatus::Active
+    } else {
+        StakeStatus::Inactive
+    }
+}
+
+/// Function to get the clock sysvar, needed to check stake account lockups
+pub async fn get_clock(rpc: &RpcClient) -> Result<Clock> {
+    let account = rpc.get_account(&sysvar::clock::id()).await?;
+
+    Ok(bincode::deserialize(&account.data)?)
+}
+
+/// Function to get the stake history sysvar, newest epoch first
+pub async fn get_stake_history(rpc: &RpcClient) -> Result<StakeHistory> {
+    let account = rpc.get_account(&sysvar::stake_history::id()).await?;
+
+    Ok(bincode::deserialize(&account.data)?)
+}
+
+/// Function to get the epoch from which the lower warmup and cooldown rate applies, None if the feature is not
+/// active on the cluster
+pub async fn get_new_rate_activation_epoch(rpc: &RpcClient) -> Result<Option<u64>> {
+    let Ok(account) = rpc.get_account(&REDUCE_STAKE_WARMUP_COOLDOWN_FEATURE).await else {
+        return Ok(None);
+    };
+
+    // A feature account holds the slot it was activated at
+    let activated_at = bincode::deserialize::<Option<u64>>(&account.data)?;
+    let epoch_schedule = rpc.get_epoch_schedule().await?;
+
+    Ok(activated_at.map(|slot| epoch_schedule.get_epoch(slot)))
+}
+
+/// Whether the lamports of a stake account can be withdrawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClaimStatus {
+    /// Still delegated, it has to be deactivated first
+    NotDeactivated,
+    /// Deactivated but part of the stake is still cooling down
+    CoolingDown {
+        /// Stake that is still effective
+        effective: u64,
+        /// Projected epoch from which the stake account can be withdrawn
+        claimable_epoch: u64,
+    },
+    /// Fully deactivated
+    Claimable,
+}
+
+/// Function to get whether the stake can be withdrawn, using the stake history for the stake that is still cooling
+/// down. The epoch it can be withdrawn is projected with the cluster stake of the latest stake history entry
+pub fn get_claim_status(
+    stake: &Stake,
+    stake_history: &StakeHistory,
+    epoch: u64,
+    new_rate_activation_epoch: Option<u64>,
+) -> ClaimStatus {
+    let delegation = &stake.delegation;
+
+    if delegation.deactivation_epoch == u64::MAX {
+        return ClaimStatus::NotDeactivated;
+    }
+
+    let status = delegation.stake_activating_and_deactivating(epoch, stake_history, new_rate_activation_epoch);
+
+    if get_activation_status(&status) == StakeStatus::Inactive {
+        return ClaimStatus::Claimable;
+    }
+
+    // Every epoch the cluster deactivates up to the cooldown rate of its effective stake, shared pro rata by all
+    // deactivating stake
+    let cooldown_ratio = stake_history
+        .first()
+        .map(|(_, entry)| {
+            entry.effective as f64 * warmup_cooldown_rate(epoch, new_rate_activation_epoch)
+                / entry.deactivating.max(1) as f64
+        })
+        .unwrap_or(1.0);
+
+    let mut effective = status.effective;
+    let mut claimable_epoch = epoch;
+
+    while claimable_epoch - epoch < MAX_COOLDOWN_EPOCHS {
+        claimable_epoch += 1;
+
+        let newly_not_effective = ((effective as f64 * cooldown_ratio) as u64).max(1);
+        if newly_not_effective >= effective {
+            break;
+        }
+
+        effective -= newly_not_effective;
+    }
+
+    ClaimStatus::CoolingDown {
+        effective: status.effective,
+        claimable_epoch,
+    }
+}
+
+/// Function to get the instruction withdrawing all lamports of the stake account, which closes it
+pub fn get_claim_stake_instruction(
+    stake_account: &WalletStakeAccount,
+    withdrawer: &Pubkey,
+    recipient: &Pubkey,
+) -> Instruction {
+    stake::instruction::withdraw(&stake_account.address, withdrawer, recipient, stake_account.lamports, None)
+}
+
+/// Function to get the reasons the liquid unstaker program would reject unstaking the stake account, empty if it
+/// would accept it
+pub fn get_unstake_stake_account_issues(stake_account: &WalletStakeAccount, clock: &Clock) -> Vec<&'static str> {
+    let mut issues = vec![];
+
+    if stake_account.meta.lockup.is_in_force(clock, None) {
+        issues.push("StakeAccountLockupIsInForce");
+    }
+
+    match stake_account.status {
+        StakeStatus::Undelegated => issues.push("InvalidStakeAccountState: not delegated"),
+        StakeStatus::Deactivating | StakeStatus::Inactive => {
+            issues.push("InvalidStakeAccountState: already deactivated")
+        }
+        StakeStatus::Activating | StakeStatus::Active => {}
+    }
+
+    issues
+}
+
+/// Function to get the lamports received for liquid unstaking a whole stake account holding `stake_account_lamports`
+/// lamports, including its rent exempt reserve, and the fee charged on it
+pub fn quote_stake_account_unstake(pool: &Pool, stake_account_lamports: u64) -> Result<(i64, Fee)> {
+    if stake_account_lamports > pool.sol_vault_lamports {
+        return Err(anyhow::anyhow!(
+            "Not enough liquidity in the unstake pool to cover this unstake amount"
+        ));
+    }
+
+    let base_fee_pct_bps = Fee::calculate_base_fee(pool, pool.sol_vault_lamports, stake_account_lamports)? as u128;
+
+    let fee = Fee {
+        base_fee: (base_fee_pct_bps * stake_account_lamports as u128 / FEE_PCT_DIVISOR as u128) as u64,
+        manager_fee: (base_fee_pct_bps * stake_account_lamports as u128 * pool.manager_fee_pct as u128
+            / (100 * FEE_PCT_DIVISOR as u128)) as u64,
+    };
+
+    Ok((stake_account_lamports as i64 - fee.total_fee() as i64, fee))
+}
+
+/// Function to check that `amount` lamports of stake can be split off the stake account, both the split off stake and
+/// the stake left behind need at least the minimum delegation
+pub fn check_split_amount(stake_account: &WalletStakeAccount, amount: u64, minimum_delegation: u64) -> Result<()> {
+    let stake_lamports = stake_account.lamports.saturating_sub(stake_account.meta.rent_exempt_reserve);
+
+    if amount < minimum_delegation {
+        return Err(anyhow::anyhow!(
+            "Cannot split {} lamports, the split stake needs at least the minimum delegation of {} lamports",
+            amount,
+            minimum_delegation
+        ));
+    }
+
+    if amount >= stake_lamports {
+        return Err(anyhow::anyhow!(
+            "Cannot split {} lamports off a stake account with {} lamports of stake, unstake the whole account instead",
+            amount,
+            stake_lamports
+        ));
+    }
+
+    if stake_lamports - amount < minimum_delegation {
+        return Err(anyhow::anyhow!(
+            "Cannot split {} lamports, the stake left behind needs at least the minimum delegation of {} lamports",
+            amount,
+            minimum_delegation
+        ));
+    }
+
+    Ok(())
+}
+
+/// Function to get the instructions splitting `amount` lamports of stake off the stake account into the new stake
+/// account `split_stake_account`, which the payer funds with its rent exempt reserve
+pub fn get_split_stake_instructions(
+    payer: &Pubkey,
+    authority: &Pubkey,
+    stake_account: &Pubkey,
+    amount: u64,
+    split_stake_account: &Pubkey,
+    rent_exempt_reserve: u64,
+) -> Vec<Instruction> {
+    let mut instructions = vec![anchor_client::solana_sdk::system_instruction::transfer(payer, split_stake_account, rent_exempt_reserve)];
+
+    instructions.extend(stake::instruction::split(stake_account, authority, amount, split_stake_account));
+
+    instructions
+}
+
+/// Function to get the liquid_unstake_stake_account instruction for the whole stake account, the SOL goes to the
+/// recipient
+#[allow(clippy::too_many_arguments)]
+pub fn get_unstake_stake_account_instructions(
+    program: &Program<Rc<Keypair>>,
+    unstake_pool_id: &Pubkey,
+    unstake_pool_info: &Pool,
+    authority: &Pubkey,
+    stake_account: &Pubkey,
+    recipient: &Pubkey,
+    minimum_lamports_out: Option<u64>,
+) -> Result<Vec<Instruction>> {
+    let (stake_account_info, _) =
+        Pubkey::find_program_address(&[b"stake_account_info", stake_account.as_ref()], &program.id());
+
+    Ok(program
+        .request()
+        .accounts(liquid_unstaker::liquid_unstaker::client::accounts::LiquidUnstakeStakeAccount {
+            pool: *unstake_pool_id,
+            user: *authority,
+            stake_account: *stake_account,
+            stake_account_info,
+            sol_vault: unstake_pool_info.sol_vault,
+            user_sol_account: *recipient,
+            manager_fee_account: unstake_pool_info.manager_fee_account,
+            stake_program: stake::program::id(),
+            token_program: anchor_spl::token::spl_token::id(),
+            system_program: anchor_client::solana_sdk::system_program::id(),
+            clock: sysvar::clock::id(),
+        })
+        .args(liquid_unstaker::liquid_unstaker::client::args::LiquidUnstakeStakeAccount {
+            minimum_lamports_out,
+        })
+        .instructions()?)
+}
+
+/// Stake accounts that can be merged into a single stake account, the first and largest account is the merge
+/// destination
+pub struct StakeAccountGroup {
+    pub voter: Pubkey,
+    pub status: StakeStatus,
+    pub stake_accounts: Vec<Pubkey>,
+    pub lamports: u64,
+}
+
+/// Function to group the stake accounts the program accepts into groups the stake program can merge: same validator,
+/// same activation state (and activation epoch while activating) and same authorities. Accounts with a lockup in
+/// force or that are not active or activating are left out
+pub fn group_stake_accounts(stake_accounts: &[WalletStakeAccount], clock: &Clock) -> Vec<StakeAccountGroup> {
+    let mut groups: Vec<(_, StakeAccountGroup)> = vec![];
+
+    for stake_account in stake_accounts.iter().sorted_by_key(|stake_account| Reverse(stake_account.lamports)) {
+        if !get_unstake_stake_account_issues(stake_account, clock).is_empty() {
+            continue;
+        }
+
+        let Some(stake) = stake_account.stake else {
+            continue;
+        };
+
+        let activation_epoch = match stake_account.status {
+            StakeStatus::Activating => Some(stake.delegation.activation_epoch),
+            _ => None,
+        };
+        let key = (
+            stake.delegation.voter_pubkey,
+            stake_account.status,
+            activation_epoch,
+            stake_account.meta.authorized.staker,
+            stake_account.meta.authorized.withdrawer,
+        );
+
+        match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+            Some((_, group)) => {
+                group.stake_accounts.push(stake_account.address);
+                group.lamports += stake_account.lamports;
+            }
+            None => groups.push((
+                key,
+                StakeAccountGroup {
+                    voter: stake.delegation.voter_pubkey,
+                    status: stake_account.status,
+                    stake_accounts: vec![stake_account.address],
+                    lamports: stake_account.lamports,
+                },
+            )),
+        }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// Function to get the instructions merging all stake accounts of the group into the first one
+pub fn get_merge_stake_instructions(group: &StakeAccountGroup, authority: &Pubkey) -> Vec<Instruction> {
+    let destination = group.stake_accounts[0];
+
+    group.stake_accounts[1..]
+        .iter()
+        .flat_map(|source| stake::instruction::merge(&destination, source, authority))
+        .collect()
+}
+
+/// Function to pack the instructions into as few transactions as fit, keeping the order. Every transaction starts
+/// with the `prefix` instructions, e.g. compute budget instructions
+pub fn pack_instructions_into_transactions(
+    instructions: Vec<Instruction>,
+    prefix: &[Instruction],
+    payer: &Pubkey,
+) -> Result<Vec<Vec<Instruction>>> {
+    let fits = |instructions: &[Instruction]| -> Result<bool> {
+        let tx = Transaction::new_with_payer(instructions, Some(payer));
+
+        Ok(bincode::serialized_size(&tx)? as usize <= PACKET_DATA_SIZE)
+    };
+
+    let mut transactions = vec![];
+    let mut current = prefix.to_vec();
+
+    for instruction in instructions {
+        current.push(instruction);
+
+        if !fits(&current)? {
+            let instruction = current.pop().unwrap();
+
+            if current.len() == prefix.len() {
+                return Err(anyhow::anyhow!("Instruction does not fit in a transaction"));
+            }
+
+            transactions.push(current);
+            current = prefix.to_vec();
+            current.push(instruction);
+        }
+    }
+
+    if current.len() > prefix.len() {
+        transactions.push(current);
+    }
+
+    Ok(transactions)
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::{compute_budget::ComputeBudgetInstruction, native_token::LAMPORTS_PER_SOL};
+    use solana_stake_interface::state::{Delegation, Lockup};
+
+    use super::*;
+
+    const RENT_EXEMPT_RESERVE: u64 = 2_282_880;
+
+    fn get_stake(voter: Pubkey, activation_epoch: u64, deactivation_epoch: u64) -> Stake {
+        Stake {
+            delegation: Delegation {
+                voter_pubkey: voter,
+                stake: 10 * LAMPORTS_PER_SOL,
+                activation_epoch,
+                deactivation_epoch,
+                ..Delegation::default()
+            },
+            ..Stake::default()
+        }
+    }
+
+    fn get_stake_account(voter: Pubkey, lamports: u64, status: StakeStatus) -> WalletStakeAccount {
+        WalletStakeAccount {
+            address: Pubkey::new_unique(),
+            lamports,
+            meta: Meta {
+                rent_exempt_reserve: RENT_EXEMPT_RESERVE,
+                ..Meta::default()
+            },
+            stake: Some(get_stake(voter, 0, u64::MAX)),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_check_split_amount() {
+        let stake_account = get_stake_account(
+            Pubkey::new_unique(),
+            10 * LAMPORTS_PER_SOL + RENT_EXEMPT_RESERVE,
+            StakeStatus::Active,
+        );
+        let minimum_delegation = LAMPORTS_PER_SOL;
+
+        assert!(check_split_amount(&stake_account, 4 * LAMPORTS_PER_SOL, minimum_delegation).is_ok());
+        assert!(check_split_amount(&stake_account, 9 * LAMPORTS_PER_SOL, minimum_delegation).is_ok());
+
+        // The split stake is below the minimum delegation
+        assert!(check_split_amount(&stake_account, LAMPORTS_PER_SOL - 1, minimum_delegation).is_err());
+        // The whole stake, or more
+        assert!(check_split_amount(&stake_account, 10 * LAMPORTS_PER_SOL, minimum_delegation).is_err());
+        assert!(check_split_amount(&stake_account, 11 * LAMPORTS_PER_SOL, minimum_delegation).is_err());
+        // The stake left behind is below the minimum delegation
+        assert!(check_split_amount(&stake_account, 9 * LAMPORTS_PER_SOL + 1, minimum_delegation).is_err());
+    }
+
+    #[test]
+    fn test_get_stake_status() {
+        let voter = Pubkey::new_unique();
+        let stake_history = StakeHistory::default();
+
+        assert_eq!(get_stake_status(None, &stake_history, 10, None), StakeStatus::Undelegated);
+        assert_eq!(
+            get_stake_status(Some(&get_stake(voter, 10, u64::MAX)), &stake_history, 10, None),
+            StakeStatus::Activating
+        );
+        assert_eq!(
+            get_stake_status(Some(&get_stake(voter, 5, u64::MAX)), &stake_history, 10, None),
+            StakeStatus::Active
+        );
+        assert_eq!(
+            get_stake_status(Some(&get_stake(voter, 5, 10)), &stake_history, 10, None),
+            StakeStatus::Deactivating
+        );
+        assert_eq!(
+            get_stake_status(Some(&get_stake(voter, 5, 8)), &stake_history, 10, None),
+            StakeStatus::Inactive
+        );
+    }
+
+    #[test]
+    fn test_get_claim_status() {
+        let voter = Pubkey::new_unique();
+        let stake_history = StakeHistory::default();
+
+        assert_eq!(
+            get_claim_status(&get_stake(voter, 5, u64::MAX), &stake_history, 10, None),
+            ClaimStatus::NotDeactivated
+        );
+        assert_eq!(
+            get_claim_status(&get_stake(voter, 5, 8), &stake_history, 10, None),
+            ClaimStatus::Claimable
+        );
+        assert_eq!(
+            get_claim_status(&get_stake(voter, 5, 10), &stake_history, 10, None),
+            ClaimStatus::CoolingDown {
+                effective: 10 * LAMPORTS_PER_SOL,
+                claimable_epoch: 11,
+            }
+        );
+    }
+
+    #[test]
+    fn test_group_stake_accounts() {
+        let voter = Pubkey::new_unique();
+        let other_voter = Pubkey::new_unique();
+        let mut locked_up = get_stake_account(voter, 7 * LAMPORTS_PER_SOL, StakeStatus::Active);
+        locked_up.meta.lockup = Lockup {
+            epoch: 10,
+            ..Lockup::default()
+        };
+
+        let stake_accounts = vec![
+            get_stake_account(voter, 2 * LAMPORTS_PER_SOL, StakeStatus::Active),
+            get_stake_account(voter, 5 * LAMPORTS_PER_SOL, StakeStatus::Active),
+            get_stake_account(voter, 3 * LAMPORTS_PER_SOL, StakeStatus::Activating),
+            get_stake_account(other_voter, 4 * LAMPORTS_PER_SOL, StakeStatus::Active),
+            get_stake_account(voter, 6 * LAMPORTS_PER_SOL, StakeStatus::Deactivating),
+            locked_up,
+        ];
+
+        let groups = group_stake_accounts(&stake_accounts, &Clock::default());
+
+        assert_eq!(groups.len(), 3);
+
+        // The largest account comes first, it is the merge destination
+        assert_eq!(groups[0].voter, voter);
+        assert_eq!(groups[0].status, StakeStatus::Active);
+        assert_eq!(groups[0].stake_accounts, vec![stake_accounts[1].address, stake_accounts[0].address]);
+        assert_eq!(groups[0].lamports, 7 * LAMPORTS_PER_SOL);
+
+        assert_eq!(groups[1].voter, other_voter);
+        assert_eq!(groups[1].stake_accounts, vec![stake_accounts[3].address]);
+
+        assert_eq!(groups[2].status, StakeStatus::Activating);
+        assert_eq!(groups[2].stake_accounts, vec![stake_accounts[2].address]);
+    }
+
+    #[test]
+    fn test_pack_instructions_into_transactions() {
+        let payer = Pubkey::new_unique();
+        let prefix = vec![ComputeBudgetInstruction::set_compute_unit_price(1)];
+        let instructions = (0..50)
+            .map(|_| stake::instruction::deactivate_stake(&Pubkey::new_unique(), &payer))
+            .collect::<Vec<_>>();
+
+        let transactions = pack_instructions_into_transactions(instructions.clone(), &prefix, &payer).unwrap();
+
+        assert!(transactions.len() > 1);
+
+        for transaction in &transactions {
+            assert_eq!(transaction[0], prefix[0]);
+
+            let tx = Transaction::new_with_payer(transaction, Some(&payer));
+            assert!(bincode::serialized_size(&tx).unwrap() as usize <= PACKET_DATA_SIZE);
+        }
+
+        let packed = transactions.into_iter().flat_map(|transaction| transaction.into_iter().skip(1)).collect::<Vec<_>>();
+        assert_eq!(packed, instructions);
+
+        assert!(pack_instructions_into_transactions(vec![], &prefix, &payer).unwrap().is_empty());
+    }
+}
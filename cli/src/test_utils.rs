@@ -0,0 +1,61 @@
+use anchor_client::solana_sdk::{native_token::LAMPORTS_PER_SOL, pubkey::Pubkey};
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use spl_stake_pool::state::{Fee as StakePoolFee, StakePool, ValidatorList, ValidatorStakeInfo};
+
+/// Pool with a 0.1% to 5% fee curve below 1000 SOL, a 10% manager fee and 2000 SOL in the vault
+pub fn get_test_pool() -> Pool {
+    Pool {
+        authority: Pubkey::default(),
+        sol_vault: Pubkey::default(),
+        lp_mint: Pubkey::default(),
+        manager_fee_account: Pubkey::default(),
+        total_lp_tokens: 2_000 * LAMPORTS_PER_SOL,
+        total_accrued_fees: 0,
+        total_deactivating_stake: 0,
+        fee_max: 5_000,
+        fee_min: 100,
+        min_sol_for_min_fee: 1_000 * LAMPORTS_PER_SOL,
+        manager_fee_pct: 10,
+        bump: 0,
+        sol_vault_bump: 0,
+        sol_vault_lamports: 2_000 * LAMPORTS_PER_SOL,
+        sol_vault_lamports_cap: u64::MAX,
+        withdraw_sol_fee: 10,
+        withdraw_stake_account_fee: 20,
+        flash_loan_fee: 0,
+        flash_loan_borrowed_amount: 0,
+        flash_loans_enabled: false,
+        total_stake_rewards_withdrawn: 0,
+        last_stake_rewards_withdrawn_epoch: 0,
+        reserved: [0; 3],
+    }
+}
+
+/// Stake pool with one LST token per lamport and no withdrawal fee
+pub fn get_test_stake_pool() -> StakePool {
+    StakePool {
+        total_lamports: 1_000_000 * LAMPORTS_PER_SOL,
+        pool_token_supply: 1_000_000 * LAMPORTS_PER_SOL,
+        stake_withdrawal_fee: StakePoolFee {
+            denominator: 1_000,
+            numerator: 0,
+        },
+        ..StakePool::default()
+    }
+}
+
+/// Validator list of active validators with the given stake
+pub fn get_test_validator_list(active_stake_lamports: &[u64]) -> ValidatorList {
+    let mut validator_list = ValidatorList::new(active_stake_lamports.len() as u32);
+
+    validator_list.validators = active_stake_lamports
+        .iter()
+        .map(|lamports| ValidatorStakeInfo {
+            active_stake_lamports: (*lamports).into(),
+            vote_account_address: Pubkey::new_unique(),
+            ..ValidatorStakeInfo::default()
+        })
+        .collect();
+
+    validator_list
+}
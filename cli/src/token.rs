@@ -0,0 +1,29 @@
+use anchor_client::{solana_client::nonblocking::rpc_client::RpcClient, solana_sdk::pubkey::Pubkey};
+use anchor_spl::{
+    token::spl_token,
+    token_2022::spl_token_2022::{self, extension::StateWithExtensions},
+};
+use anyhow::Result;
+
+/// Function to get the token program (SPL Token or Token-2022) owning the given mint
+pub async fn get_token_program_for_mint(rpc: &RpcClient, mint: &Pubkey) -> Result<Pubkey> {
+    let mint_account = rpc
+        .get_account(mint)
+        .await
+        .map_err(|_| anyhow::anyhow!("Mint {} not found", mint))?;
+
+    if mint_account.owner != spl_token::id() && mint_account.owner != spl_token_2022::id() {
+        return Err(anyhow::anyhow!(
+            "Mint {} is owned by {}, which is not a token program",
+            mint,
+            mint_account.owner
+        ));
+    }
+
+    Ok(mint_account.owner)
+}
+
+/// Unpack an SPL Token or Token-2022 token account, ignoring any Token-2022 extensions
+pub fn unpack_token_account(data: &[u8]) -> Result<spl_token_2022::state::Account> {
+    Ok(StateWithExtensions::<spl_token_2022::state::Account>::unpack(data)?.base)
+}
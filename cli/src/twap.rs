@@ -0,0 +1,329 @@
+use std::{fs, path::Path, rc::Rc, str::FromStr};
+
+use anchor_client::{
+    solana_sdk::{
+        hash::Hash,
+        pubkey::Pubkey,
+        signature::{Keypair, Signature},
+        signer::Signer,
+    },
+    Program,
+};
+use anchor_spl::token_interface::spl_token_metadata_interface::borsh::BorshDeserialize;
+use anyhow::Result;
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    batch::{self, PendingOutcome},
+    build_unstake_lst_transaction, get_stake_pool_for_lst_mint,
+    max_unstake::{self, MaxUnstakeConstraints},
+    send_or_simulate_transaction, send_or_simulate_transaction_checked, PubkeyOrKeypair, UnstakeLstOptions,
+};
+
+/// Over what the amount of a TWAP unstake is spread
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TwapSchedule {
+    /// Released linearly over this many seconds
+    Duration(u64),
+    /// Released in equal parts at the start of this many epochs, the first one being the current epoch
+    Epochs(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TwapSliceStatus {
+    /// The transaction was sent but it is not known yet whether it landed
+    Pending,
+    Landed,
+    Failed,
+}
+
+/// A single unstake of a TWAP unstake
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TwapSlice {
+    pub pool_tokens: u64,
+    pub timestamp: i64,
+    pub status: TwapSliceStatus,
+    pub signature: String,
+    pub blockhash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lamports_received: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Progress of a TWAP unstake, persisted after every change so an interrupted TWAP unstake resumes where it stopped
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TwapState {
+    pub pool: String,
+    pub mint: String,
+    pub amount: u64,
+    pub schedule: TwapSchedule,
+    /// Maximum fee on the last lamport of every slice, in percent
+    pub max_marginal_fee_pct: f64,
+    pub start_timestamp: i64,
+    pub start_epoch: u64,
+    pub slices: Vec<TwapSlice>,
+}
+
+impl TwapState {
+    /// LST tokens released by the schedule at the given time and epoch
+    pub fn get_scheduled_pool_tokens(&self, now: i64, epoch: u64) -> u64 {
+        let (elapsed, total) = match self.schedule {
+            TwapSchedule::Duration(seconds) => ((now - self.start_timestamp).max(0) as u64, seconds),
+            TwapSchedule::Epochs(epochs) => (epoch.saturating_sub(self.start_epoch) + 1, epochs),
+        };
+
+        if total == 0 || elapsed >= total {
+            return self.amount;
+        }
+
+        (self.amount as u128 * elapsed as u128 / total as u128) as u64
+    }
+
+    /// LST tokens unstaked so far, counting the slices that may still land
+    pub fn get_unstaked_pool_tokens(&self) -> u64 {
+        self.slices
+            .iter()
+            .filter(|slice| slice.status != TwapSliceStatus::Failed)
+            .map(|slice| slice.pool_tokens)
+            .sum()
+    }
+
+    /// Lamports received for the slices that landed
+    pub fn get_lamports_received(&self) -> i64 {
+        self.slices
+            .iter()
+            .filter(|slice| slice.status == TwapSliceStatus::Landed)
+            .filter_map(|slice| slice.lamports_received)
+            .sum()
+    }
+}
+
+/// Function to read the progress of a TWAP unstake, None if it has not started yet
+pub fn read_twap_state(path: &Path) -> Result<Option<TwapState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map(Some)
+        .map_err(|err| anyhow::anyhow!("Invalid TWAP state {}: {}", path.display(), err))
+}
+
+/// Function to write the progress of a TWAP unstake, through a temporary file so a crash never leaves half a state
+/// behind
+pub fn write_twap_state(path: &Path, state: &TwapState) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temporary_path = path.with_extension("json.tmp");
+    fs::write(&temporary_path, serde_json::to_string_pretty(state)?)?;
+    fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+/// Run one step of a TWAP unstake: settle the pending slices, then unstake what the schedule released so far as a
+/// single slice, sized down until the marginal fee stays under the cap. When no size meets the cap the slice waits
+/// for the vault to refill. Returns whether the whole amount has been unstaked
+#[allow(clippy::too_many_arguments)]
+pub async fn run_twap_step(
+    program: &Program<Rc<Keypair>>,
+    fee_payer: &Keypair,
+    wallet_keypair: &Keypair,
+    spl_stake_pool_program_id: &Pubkey,
+    path: &Path,
+    state: &mut TwapState,
+    simulate: bool,
+    new_stake_account_as_pda: bool,
+    options: &UnstakeLstOptions<'_>,
+) -> Result<bool> {
+    let rpc = program.rpc();
+    let recipient = options.recipient.unwrap_or(wallet_keypair.pubkey());
+
+    for index in 0..state.slices.len() {
+        if state.slices[index].status != TwapSliceStatus::Pending {
+            continue;
+        }
+
+        let signature = Signature::from_str(&state.slices[index].signature)?;
+        let blockhash = Hash::from_str(&state.slices[index].blockhash)?;
+
+        let (status, error) = match batch::resolve_pending(&rpc, &signature, &blockhash).await? {
+            PendingOutcome::Landed => (TwapSliceStatus::Landed, None),
+            PendingOutcome::StillPending => continue,
+            PendingOutcome::Failed(err) => (TwapSliceStatus::Failed, Some(err)),
+            PendingOutcome::Dropped => (TwapSliceStatus::Failed, Some("Transaction dropped".to_string())),
+        };
+
+        state.slices[index].status = status;
+        state.slices[index].error = error;
+
+        if status == TwapSliceStatus::Landed {
+            state.slices[index].lamports_received = batch::get_lamports_received(&rpc, &signature, &recipient).await.ok();
+        }
+
+        write_twap_state(path, state)?;
+    }
+
+    let unstaked = state.get_unstaked_pool_tokens();
+
+    if unstaked >= state.amount {
+        return Ok(state.slices.iter().all(|slice| slice.status == TwapSliceStatus::Landed));
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let epoch = rpc.get_epoch_info().await?.epoch;
+    let due = state.get_scheduled_pool_tokens(now, epoch).saturating_sub(unstaked);
+
+    if due == 0 {
+        return Ok(false);
+    }
+
+    let pool_id = Pubkey::from_str(&state.pool)?;
+    let mint = Pubkey::from_str(&state.mint)?;
+
+    let unstake_pool_info = program.account::<Pool>(pool_id).await?;
+    let (_, spl_stake_pool_state) = get_stake_pool_for_lst_mint(&rpc, &mint, spl_stake_pool_program_id).await?;
+    let spl_stake_pool_validator_list = rpc
+        .get_account(&spl_stake_pool_state.validator_list)
+        .await
+        .map(|account| {
+            let mut data = account.data.as_slice();
+            spl_stake_pool::state::ValidatorList::deserialize(&mut data)
+        })??;
+
+    let quote = match max_unstake::find_max_unstake(
+        &spl_stake_pool_state,
+        &spl_stake_pool_validator_list,
+        &unstake_pool_info,
+        &MaxUnstakeConstraints {
+            max_marginal_fee_pct: Some(state.max_marginal_fee_pct),
+            max_pool_tokens: Some(due),
+            ..Default::default()
+        },
+    ) {
+        Ok(quote) => quote,
+        Err(_) => {
+            println!("{} tokens due but the marginal fee is above the cap, waiting for the vault to refill", due);
+
+            return Ok(false);
+        }
+    };
+
+    println!(
+        "Unstaking a slice of {} of {} due tokens, marginal fee {:.4}%, quote {} lamports",
+        quote.pool_tokens, due, quote.marginal_fee_pct, quote.lamports_out
+    );
+
+    let (tx, simulation_accounts_of_interest) = build_unstake_lst_transaction(
+        program,
+        &pool_id,
+        fee_payer,
+        &PubkeyOrKeypair::Keypair(wallet_keypair.insecure_clone()),
+        spl_stake_pool_program_id,
+        &mint,
+        &unstake_pool_info,
+        quote.pool_tokens,
+        new_stake_account_as_pda,
+        options,
+    )
+    .await?;
+
+    if simulate {
+        send_or_simulate_transaction(&rpc, &tx, true, Some(simulation_accounts_of_interest)).await?;
+
+        return Ok(true);
+    }
+
+    // Record the slice before sending, so a restarted TWAP unstake can tell whether it landed
+    state.slices.push(TwapSlice {
+        pool_tokens: quote.pool_tokens,
+        timestamp: now,
+        status: TwapSliceStatus::Pending,
+        signature: tx.signatures[0].to_string(),
+        blockhash: tx.message.recent_blockhash.to_string(),
+        lamports_received: None,
+        error: None,
+    });
+    write_twap_state(path, state)?;
+
+    if let Err(err) = send_or_simulate_transaction_checked(&rpc, &tx, false, None).await {
+        println!("Slice failed: {}", err);
+    }
+
+    // The next step settles the slice
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_twap_state(schedule: TwapSchedule) -> TwapState {
+        TwapState {
+            pool: Pubkey::default().to_string(),
+            mint: Pubkey::default().to_string(),
+            amount: 1_000,
+            schedule,
+            max_marginal_fee_pct: 1.0,
+            start_timestamp: 1_000,
+            start_epoch: 500,
+            slices: vec![],
+        }
+    }
+
+    fn get_slice(pool_tokens: u64, status: TwapSliceStatus, lamports_received: Option<i64>) -> TwapSlice {
+        TwapSlice {
+            pool_tokens,
+            timestamp: 0,
+            status,
+            signature: Signature::default().to_string(),
+            blockhash: Hash::default().to_string(),
+            lamports_received,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_get_scheduled_pool_tokens_over_duration() {
+        let state = get_twap_state(TwapSchedule::Duration(100));
+
+        assert_eq!(state.get_scheduled_pool_tokens(900, 500), 0);
+        assert_eq!(state.get_scheduled_pool_tokens(1_000, 500), 0);
+        assert_eq!(state.get_scheduled_pool_tokens(1_025, 500), 250);
+        assert_eq!(state.get_scheduled_pool_tokens(1_100, 500), 1_000);
+        assert_eq!(state.get_scheduled_pool_tokens(5_000, 500), 1_000);
+
+        assert_eq!(get_twap_state(TwapSchedule::Duration(0)).get_scheduled_pool_tokens(1_000, 500), 1_000);
+    }
+
+    #[test]
+    fn test_get_scheduled_pool_tokens_over_epochs() {
+        let state = get_twap_state(TwapSchedule::Epochs(4));
+
+        // The first part is released in the start epoch
+        assert_eq!(state.get_scheduled_pool_tokens(0, 500), 250);
+        assert_eq!(state.get_scheduled_pool_tokens(0, 502), 750);
+        assert_eq!(state.get_scheduled_pool_tokens(0, 503), 1_000);
+        assert_eq!(state.get_scheduled_pool_tokens(0, 510), 1_000);
+    }
+
+    #[test]
+    fn test_unstaked_pool_tokens_and_lamports_received() {
+        let mut state = get_twap_state(TwapSchedule::Epochs(4));
+        state.slices = vec![
+            get_slice(250, TwapSliceStatus::Landed, Some(240)),
+            get_slice(250, TwapSliceStatus::Failed, None),
+            get_slice(100, TwapSliceStatus::Pending, None),
+        ];
+
+        // Pending slices may still land, failed ones are unstaked again
+        assert_eq!(state.get_unstaked_pool_tokens(), 350);
+        assert_eq!(state.get_lamports_received(), 240);
+    }
+}
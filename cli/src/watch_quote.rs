@@ -0,0 +1,313 @@
+use std::time::Duration;
+
+use anchor_client::{
+    solana_client::{
+        nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient},
+        rpc_config::RpcAccountInfoConfig,
+    },
+    solana_sdk::{account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey},
+};
+use anchor_lang::AccountDeserialize;
+use anchor_spl::token_interface::spl_token_metadata_interface::borsh::BorshDeserialize;
+use anyhow::Result;
+use futures::{stream, StreamExt};
+use liquid_unstaker::liquid_unstaker::accounts::Pool;
+use serde::Serialize;
+use solana_account_decoder::UiAccountEncoding;
+use spl_stake_pool::state::{StakePool, ValidatorList};
+
+use crate::{max_unstake, quote_lst_unstake_breakdown};
+
+/// Wait before the first reconnection attempt, doubled after every failed attempt
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest wait between two reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// What caused the quotes to be recomputed
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuoteTrigger {
+    /// The accounts were fetched after (re)connecting
+    Snapshot,
+    Pool,
+    StakePool,
+    ValidatorList,
+}
+
+/// A quote streamed as a JSON line
+#[derive(Serialize)]
+pub struct QuoteLine {
+    /// Slot of the account update the quote is computed from
+    pub slot: u64,
+    pub timestamp: i64,
+    pub trigger: QuoteTrigger,
+    pub mint: String,
+    pub amount: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lamports_out: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_fee_pct: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub marginal_fee_pct: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Latest state of the accounts the quotes depend on, with the slot each was last updated at
+struct QuoteAccounts {
+    pool: Pool,
+    stake_pool: StakePool,
+    validator_list: ValidatorList,
+    slots: [u64; 3],
+}
+
+/// Function to quote unstaking each of the amounts against the given accounts
+pub fn get_quote_lines(
+    pool: &Pool,
+    stake_pool: &StakePool,
+    validator_list: &ValidatorList,
+    mint: &Pubkey,
+    amounts: &[u64],
+    slot: u64,
+    trigger: QuoteTrigger,
+) -> Vec<QuoteLine> {
+    let max_pool_tokens = max_unstake::get_max_pool_tokens_for_stake_accounts(
+        stake_pool,
+        validator_list,
+        max_unstake::MAX_STAKE_ACCOUNTS_PER_UNSTAKE,
+    );
+    let timestamp = chrono::Utc::now().timestamp();
+
+    amounts
+        .iter()
+        .map(|amount| {
+            let quote = if *amount > max_pool_tokens {
+                Err(anyhow::anyhow!(
+                    "More than the {} tokens a single unstake can withdraw from the stake pool",
+                    max_pool_tokens
+                ))
+            } else {
+                quote_lst_unstake_breakdown(stake_pool, pool, *amount)
+            };
+
+            let mut line = QuoteLine {
+                slot,
+                timestamp,
+                trigger,
+                mint: mint.to_string(),
+                amount: *amount,
+                lamports_out: None,
+                effective_fee_pct: None,
+                marginal_fee_pct: None,
+                error: None,
+            };
+
+            match quote {
+                Ok(quote) => {
+                    line.lamports_out = Some(quote.lamports_out);
+                    line.effective_fee_pct = Some(quote.effective_fee_pct);
+                    line.marginal_fee_pct = Some(quote.marginal_fee_pct);
+                }
+                Err(err) => line.error = Some(err.to_string()),
+            }
+
+            line
+        })
+        .collect()
+}
+
+/// Function to stream quotes for the amounts as JSON lines to stdout, recomputed on every update of the unstake pool,
+/// the stake pool or its validator list. Lost connections are reestablished with a backoff, so this only returns on
+/// an error that reconnecting cannot fix
+#[allow(clippy::too_many_arguments)]
+pub async fn watch_quotes(
+    rpc: &RpcClient,
+    websocket_url: &str,
+    commitment: CommitmentConfig,
+    pool_id: &Pubkey,
+    stake_pool_address: &Pubkey,
+    validator_list_address: &Pubkey,
+    mint: &Pubkey,
+    amounts: &[u64],
+) -> Result<()> {
+    let mut reconnect_delay = MIN_RECONNECT_DELAY;
+
+    loop {
+        let result = watch_quotes_until_disconnected(
+            rpc,
+            websocket_url,
+            commitment,
+            [*pool_id, *stake_pool_address, *validator_list_address],
+            mint,
+            amounts,
+            &mut reconnect_delay,
+        )
+        .await;
+
+        // Status goes to stderr, stdout only has the quotes
+        match result {
+            Ok(()) => eprintln!("Subscription closed, reconnecting in {}s", reconnect_delay.as_secs()),
+            Err(err) => eprintln!("Subscription failed: {}, reconnecting in {}s", err, reconnect_delay.as_secs()),
+        }
+
+        tokio::time::sleep(reconnect_delay).await;
+        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Function to subscribe to the pool, the stake pool and the validator list and stream quotes until the connection
+/// is lost. The accounts are fetched once subscribed, so no update between fetching and subscribing is missed
+async fn watch_quotes_until_disconnected(
+    rpc: &RpcClient,
+    websocket_url: &str,
+    commitment: CommitmentConfig,
+    addresses: [Pubkey; 3],
+    mint: &Pubkey,
+    amounts: &[u64],
+    reconnect_delay: &mut Duration,
+) -> Result<()> {
+    let pubsub_client = PubsubClient::new(websocket_url).await?;
+    let config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment: Some(commitment),
+        ..RpcAccountInfoConfig::default()
+    };
+
+    let mut updates = vec![];
+
+    for (address, trigger) in addresses
+        .iter()
+        .zip([QuoteTrigger::Pool, QuoteTrigger::StakePool, QuoteTrigger::ValidatorList])
+    {
+        let (account_updates, _unsubscribe) = pubsub_client.account_subscribe(address, Some(config.clone())).await?;
+        updates.push(account_updates.map(move |update| (trigger, update)).boxed());
+    }
+
+    let mut updates = stream::select_all(updates);
+
+    let snapshot = rpc.get_multiple_accounts_with_commitment(&addresses, commitment).await?;
+    let slot = snapshot.context.slot;
+    let [pool, stake_pool, validator_list] = <[Option<Account>; 3]>::try_from(snapshot.value)
+        .map_err(|_| anyhow::anyhow!("Unexpected number of accounts"))?
+        .map(|account| account.ok_or(anyhow::anyhow!("Account not found")));
+
+    let mut accounts = QuoteAccounts {
+        pool: Pool::try_deserialize(&mut pool?.data.as_slice())?,
+        stake_pool: StakePool::deserialize(&mut stake_pool?.data.as_slice())?,
+        validator_list: ValidatorList::deserialize(&mut validator_list?.data.as_slice())?,
+        slots: [slot; 3],
+    };
+
+    *reconnect_delay = MIN_RECONNECT_DELAY;
+
+    let print_quote_lines = |accounts: &QuoteAccounts, slot: u64, trigger: QuoteTrigger| -> Result<()> {
+        for line in get_quote_lines(
+            &accounts.pool,
+            &accounts.stake_pool,
+            &accounts.validator_list,
+            mint,
+            amounts,
+            slot,
+            trigger,
+        ) {
+            println!("{}", serde_json::to_string(&line)?);
+        }
+
+        Ok(())
+    };
+
+    print_quote_lines(&accounts, slot, QuoteTrigger::Snapshot)?;
+
+    while let Some((trigger, update)) = updates.next().await {
+        let slot = update.context.slot;
+        let index = match trigger {
+            QuoteTrigger::Pool => 0,
+            QuoteTrigger::StakePool => 1,
+            _ => 2,
+        };
+
+        // Updates older than the snapshot are already part of it
+        if slot < accounts.slots[index] {
+            continue;
+        }
+
+        let account = update
+            .value
+            .decode::<Account>()
+            .ok_or(anyhow::anyhow!("Unable to decode account update"))?;
+        let mut data = account.data.as_slice();
+
+        match trigger {
+            QuoteTrigger::Pool => accounts.pool = Pool::try_deserialize(&mut data)?,
+            QuoteTrigger::StakePool => accounts.stake_pool = StakePool::deserialize(&mut data)?,
+            _ => accounts.validator_list = ValidatorList::deserialize(&mut data)?,
+        }
+        accounts.slots[index] = slot;
+
+        print_quote_lines(&accounts, slot, trigger)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anchor_client::solana_sdk::native_token::LAMPORTS_PER_SOL;
+
+    use super::*;
+    use crate::test_utils::{get_test_pool, get_test_stake_pool, get_test_validator_list};
+
+    #[test]
+    fn test_get_quote_lines() {
+        let pool = get_test_pool();
+        let stake_pool = get_test_stake_pool();
+        let validator_list = get_test_validator_list(&[100 * LAMPORTS_PER_SOL]);
+        let mint = Pubkey::new_unique();
+
+        let lines = get_quote_lines(
+            &pool,
+            &stake_pool,
+            &validator_list,
+            &mint,
+            &[10 * LAMPORTS_PER_SOL, 101 * LAMPORTS_PER_SOL],
+            1_234,
+            QuoteTrigger::Pool,
+        );
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.slot == 1_234 && line.trigger == QuoteTrigger::Pool));
+
+        let quote = quote_lst_unstake_breakdown(&stake_pool, &pool, 10 * LAMPORTS_PER_SOL).unwrap();
+        assert_eq!(lines[0].amount, 10 * LAMPORTS_PER_SOL);
+        assert_eq!(lines[0].lamports_out, Some(quote.lamports_out));
+        assert_eq!(lines[0].marginal_fee_pct, Some(quote.marginal_fee_pct));
+        assert!(lines[0].error.is_none());
+
+        // More than the validator stake accounts hold
+        assert!(lines[1].lamports_out.is_none());
+        assert!(lines[1].error.is_some());
+    }
+
+    #[test]
+    fn test_quote_line_json() {
+        let line = QuoteLine {
+            slot: 1,
+            timestamp: 2,
+            trigger: QuoteTrigger::StakePool,
+            mint: Pubkey::default().to_string(),
+            amount: 3,
+            lamports_out: None,
+            effective_fee_pct: None,
+            marginal_fee_pct: None,
+            error: Some("Not enough liquidity".to_string()),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            format!(
+                r#"{{"slot":1,"timestamp":2,"trigger":"stake_pool","mint":"{}","amount":3,"error":"Not enough liquidity"}}"#,
+                Pubkey::default()
+            )
+        );
+    }
+}